@@ -0,0 +1,196 @@
+use std::{fs::File, io::BufWriter, io::Error, io::ErrorKind, path::Path};
+#[cfg(feature = "frontend")]
+use rodio::{buffer::SamplesBuffer, OutputStream, Sink};
+
+pub const SAMPLE_RATE: u32 = 44100;
+// 44100 divides evenly into 60 frames a second, so every frame has the same number of samples
+pub const SAMPLES_PER_FRAME: usize = SAMPLE_RATE as usize / crate::machine::FRAMES_PER_SECOND as usize;
+
+// Somewhere for the mixed sound to go, given one frame of mono samples at SAMPLE_RATE at a time
+pub trait AudioOutput {
+  fn play(&mut self, samples: &[f32]);
+}
+
+// For running without a sound device, such as in tests and batch tools
+#[derive(Default)]
+pub struct NullAudio;
+
+impl AudioOutput for NullAudio {
+  fn play(&mut self, _samples: &[f32]) {}
+}
+
+// Plays on the default output device
+#[cfg(feature = "frontend")]
+pub struct RodioAudio {
+  _stream: OutputStream,
+  sink: Sink,
+}
+
+// If emulation gets ahead of the sound device, frames are dropped rather than letting the delay grow
+#[cfg(feature = "frontend")]
+const MAX_QUEUED_FRAMES: usize = 6;
+
+#[cfg(feature = "frontend")]
+impl RodioAudio {
+  pub fn new() -> std::io::Result<Self> {
+    let (stream, stream_handle) = OutputStream::try_default().map_err(Error::other)?;
+    let sink = Sink::try_new(&stream_handle).map_err(Error::other)?;
+
+    Ok(RodioAudio {
+      _stream: stream,
+      sink,
+    })
+  }
+}
+
+#[cfg(feature = "frontend")]
+impl AudioOutput for RodioAudio {
+  fn play(&mut self, samples: &[f32]) {
+    if self.sink.len() < MAX_QUEUED_FRAMES {
+      self.sink.append(SamplesBuffer::new(1, SAMPLE_RATE, samples.to_vec()));
+    }
+  }
+}
+
+// The sound hardware, driven by the bits latched on ports 3 and 5 and producing one sample at a time
+pub trait SoundBoard {
+  fn set_ports(&mut self, port3: u8, port5: u8);
+  // Called after a save state is loaded, to bring sounds that keep playing in line with the restored ports
+  // Unlike set_ports, this must not start any one-shot sounds
+  fn restore(&mut self, port3: u8, port5: u8);
+  fn next_sample(&mut self) -> f32;
+}
+
+// Runs the sound board in step with the emulated clock, and sends each frame of samples to the output and optionally a WAV
+pub struct Mixer {
+  board: Box<dyn SoundBoard>,
+  output: Box<dyn AudioOutput>,
+  wav: Option<hound::WavWriter<BufWriter<File>>>,
+  samples: Vec<f32>,
+  volume: f32,
+}
+
+impl Mixer {
+  pub fn new(board: Box<dyn SoundBoard>, output: Box<dyn AudioOutput>) -> Self {
+    Mixer {
+      board,
+      output,
+      wav: None,
+      samples: Vec::with_capacity(SAMPLES_PER_FRAME),
+      volume: 0.5,
+    }
+  }
+
+  pub fn set_output(&mut self, output: Box<dyn AudioOutput>) {
+    self.output = output;
+  }
+
+  pub fn set_board(&mut self, board: Box<dyn SoundBoard>) {
+    self.board = board;
+  }
+
+  // The port changes at the given sample of the current frame, so everything before it is generated first
+  pub fn set_ports(&mut self, sample: usize, port3: u8, port5: u8) {
+    self.generate_until(sample);
+    self.board.set_ports(port3, port5);
+  }
+
+  pub fn restore(&mut self, port3: u8, port5: u8) {
+    self.board.restore(port3, port5);
+  }
+
+  pub fn end_frame(&mut self) -> std::io::Result<()> {
+    self.generate_until(SAMPLES_PER_FRAME);
+    self.output.play(&self.samples);
+    let result = match &mut self.wav {
+      Some(wav) => self.samples.iter()
+        .try_for_each(|&sample| wav.write_sample((sample * i16::MAX as f32) as i16))
+        .map_err(Error::other),
+      None => Ok(()),
+    };
+    self.samples.clear();
+    // Once writing has failed the file is no use, so recording stops
+    if result.is_err() {
+      self.wav = None;
+    }
+
+    result
+  }
+
+  pub fn start_wav(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+    let spec = hound::WavSpec {
+      channels: 1,
+      sample_rate: SAMPLE_RATE,
+      bits_per_sample: 16,
+      sample_format: hound::SampleFormat::Int,
+    };
+    self.stop_wav()?;
+    self.wav = Some(hound::WavWriter::create(path, spec).map_err(|error| match error {
+      hound::Error::IoError(error) => error,
+      error => Error::new(ErrorKind::InvalidInput, error),
+    })?);
+
+    Ok(())
+  }
+
+  pub fn stop_wav(&mut self) -> std::io::Result<()> {
+    match self.wav.take() {
+      Some(wav) => wav.finalize().map_err(Error::other),
+      None => Ok(()),
+    }
+  }
+
+  pub fn is_recording_wav(&self) -> bool {
+    self.wav.is_some()
+  }
+
+  fn generate_until(&mut self, sample: usize) {
+    while self.samples.len() < sample.min(SAMPLES_PER_FRAME) {
+      let value = self.board.next_sample() * self.volume;
+      self.samples.push(value.clamp(-1.0, 1.0));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Plays the value of port 3 as a constant level
+  #[derive(Default)]
+  struct LevelBoard {
+    level: f32,
+  }
+
+  impl SoundBoard for LevelBoard {
+    fn set_ports(&mut self, port3: u8, _port5: u8) {
+      self.level = port3 as f32 / 4.0;
+    }
+
+    fn restore(&mut self, port3: u8, port5: u8) {
+      self.set_ports(port3, port5);
+    }
+
+    fn next_sample(&mut self) -> f32 {
+      self.level
+    }
+  }
+
+  #[test]
+  fn mixer_follows_the_emulated_clock() {
+    let mut mixer = Mixer::new(Box::new(LevelBoard::default()), Box::new(NullAudio));
+    mixer.set_ports(100, 0x01, 0x00);
+    mixer.set_ports(200, 0x00, 0x00);
+    assert_eq!(mixer.samples.len(), 200);
+    assert!(mixer.samples[..100].iter().all(|&sample| sample == 0.0));
+    assert!(mixer.samples[100..].iter().all(|&sample| sample == 0.125));
+    mixer.end_frame().unwrap();
+    assert!(mixer.samples.is_empty());
+
+    // Changes past the end of the frame wait for the next one
+    mixer.set_ports(SAMPLES_PER_FRAME + 10, 0x08, 0x00);
+    assert_eq!(mixer.samples.len(), SAMPLES_PER_FRAME);
+    assert!(mixer.samples.iter().all(|&sample| sample == 0.0));
+    mixer.end_frame().unwrap();
+  }
+}
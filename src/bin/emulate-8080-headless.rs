@@ -0,0 +1,251 @@
+// Runs Space Invaders without a window or sound, for regression tests and bots
+use emulate_8080::gdb;
+use emulate_8080::intel8080::Registers;
+use emulate_8080::machine::Machine;
+use emulate_8080::movie::{InputEvent, Movie};
+use emulate_8080::recorder::GifRecorder;
+use emulate_8080::video::ScreenshotOptions;
+use serde::Serialize;
+use std::{io::Error, io::ErrorKind, net::TcpListener, path::PathBuf};
+
+const USAGE: &str = "Usage: emulate-8080-headless [options]
+  --roms <file>          Space Invaders ROM zip (defaults to the built-in ROMs)
+  --frames <n>           Number of frames to run (default 600)
+  --until <addr>=<value> Stop early once the memory at addr holds value (both hex)
+  --input <file>         Input script, with lines of the form `<frame> press|release <key>`
+  --play-movie <file>    Replay a movie from the state it was recorded from, instead of using an input script
+  --record-movie <file>  Record the run as a movie
+  --png <file>           Write the final screen as a PNG
+  --scale <n>            Scale the PNG up by a whole number (default 1)
+  --monochrome           Leave the overlay out of the PNG
+  --json <file>          Write the final registers and RAM as JSON
+  --gif <file>           Record every frame that is run as an animated GIF
+  --wav <file>           Record the sound of every frame that is run
+  --gdb <port>           Wait for GDB to connect on 127.0.0.1:<port> and let it control the machine until it detaches,
+                         then carry on with the run";
+
+struct Options {
+  roms: Option<PathBuf>,
+  frames: u64,
+  until: Option<(u16, u8)>,
+  input: Option<PathBuf>,
+  play_movie: Option<PathBuf>,
+  record_movie: Option<PathBuf>,
+  png: Option<PathBuf>,
+  scale: u32,
+  monochrome: bool,
+  json: Option<PathBuf>,
+  gif: Option<PathBuf>,
+  wav: Option<PathBuf>,
+  gdb: Option<u16>,
+}
+
+#[derive(Serialize)]
+struct Dump<'a> {
+  frame: u64,
+  registers: Registers,
+  ram: &'a [u8],
+}
+
+fn invalid_input(message: String) -> Error {
+  Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn parse_hex<T: TryFrom<u32>>(text: &str) -> std::io::Result<T> {
+  u32::from_str_radix(text.trim_start_matches("0x"), 16).ok()
+    .and_then(|value| T::try_from(value).ok())
+    .ok_or_else(|| invalid_input(format!("Invalid hex value {}", text)))
+}
+
+fn parse_options() -> std::io::Result<Options> {
+  let mut options = Options {
+    roms: None,
+    frames: 600,
+    until: None,
+    input: None,
+    play_movie: None,
+    record_movie: None,
+    png: None,
+    scale: 1,
+    monochrome: false,
+    json: None,
+    gif: None,
+    wav: None,
+    gdb: None,
+  };
+
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    let mut value = || args.next().ok_or_else(|| invalid_input(format!("Missing value for {}\n{}", arg, USAGE)));
+    match arg.as_str() {
+      "--roms" => options.roms = Some(value()?.into()),
+      "--frames" => options.frames = value()?.parse().map_err(|_| invalid_input(USAGE.to_string()))?,
+      "--until" => {
+        let condition = value()?;
+        let (address, byte) = condition.split_once('=').ok_or_else(|| invalid_input(USAGE.to_string()))?;
+        options.until = Some((parse_hex(address)?, parse_hex(byte)?));
+      }
+      "--input" => options.input = Some(value()?.into()),
+      "--play-movie" => options.play_movie = Some(value()?.into()),
+      "--record-movie" => options.record_movie = Some(value()?.into()),
+      "--png" => options.png = Some(value()?.into()),
+      "--scale" => options.scale = value()?.parse().map_err(|_| invalid_input(USAGE.to_string()))?,
+      "--monochrome" => options.monochrome = true,
+      "--json" => options.json = Some(value()?.into()),
+      "--gif" => options.gif = Some(value()?.into()),
+      "--wav" => options.wav = Some(value()?.into()),
+      "--gdb" => options.gdb = Some(value()?.parse().map_err(|_| invalid_input(USAGE.to_string()))?),
+      _ => return Err(invalid_input(format!("Unknown option {}\n{}", arg, USAGE))),
+    }
+  }
+
+  Ok(options)
+}
+
+fn parse_input_script(script: &str) -> std::io::Result<Vec<InputEvent>> {
+  let mut events = Vec::new();
+  for (number, line) in script.lines().enumerate() {
+    let line = line.split('#').next().unwrap().trim();
+    if line.is_empty() {
+      continue;
+    }
+
+    let invalid = || invalid_input(format!("Invalid input on line {}: {}", number + 1, line));
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (frame, action, key) = match fields[..] {
+      [frame, action, key] => (frame, action, key),
+      _ => return Err(invalid()),
+    };
+    events.push(InputEvent {
+      frame: frame.parse().map_err(|_| invalid())?,
+      pressed: match action {
+        "press" => true,
+        "release" => false,
+        _ => return Err(invalid()),
+      },
+      key: key.parse().map_err(|_| invalid())?,
+    });
+  }
+  events.sort_by_key(|event| event.frame);
+
+  Ok(events)
+}
+
+fn main() -> std::io::Result<()> {
+  let options = match parse_options() {
+    Ok(options) => options,
+    Err(error) => {
+      eprintln!("{}", error);
+      std::process::exit(2);
+    }
+  };
+
+  let mut emulator = Machine::new();
+  let result = match &options.roms {
+    Some(path) => emulator.load_rom_zip(std::fs::File::open(path)?),
+    None => emulator.load_rom_zip(std::io::Cursor::new(include_bytes!("../../roms/invaders.zip"))),
+  };
+  result.map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+  if let Some(path) = &options.play_movie {
+    emulator.play_movie(Movie::load(path)?)?;
+  }
+  if options.record_movie.is_some() {
+    emulator.start_movie_recording();
+  }
+
+  let events = match &options.input {
+    Some(path) => parse_input_script(&std::fs::read_to_string(path)?)?,
+    None => Vec::new(),
+  };
+  let mut events = events.iter().peekable();
+  let mut recorder = match &options.gif {
+    Some(path) => Some(GifRecorder::create(path, options.scale)?),
+    None => None,
+  };
+  if let Some(path) = &options.wav {
+    emulator.start_wav_recording(path)?;
+  }
+
+  if let Some(port) = options.gdb {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for GDB on {}", listener.local_addr()?);
+    let (stream, address) = listener.accept()?;
+    println!("GDB connected from {}", address);
+    gdb::serve(&mut emulator, stream)?;
+  }
+
+  // Frames are counted from the start of the run, even if a movie started from a later state
+  let start_frame = emulator.frame_count();
+  while emulator.frame_count() - start_frame < options.frames {
+    let frame = emulator.frame_count() - start_frame;
+    while let Some(event) = events.next_if(|event| event.frame <= frame) {
+      match event.pressed {
+        true => emulator.key_down(event.key),
+        false => emulator.key_up(event.key),
+      }
+    }
+
+    // Anything GDB left behind doesn't stop the run
+    while emulator.run_frame().is_some() {
+      emulator.resume();
+    }
+    if let Some(recorder) = &mut recorder {
+      recorder.add_frame(&emulator)?;
+    }
+    if let Some(error) = emulator.take_wav_error() {
+      return Err(error);
+    }
+
+    if let Some((address, value)) = options.until {
+      if emulator.read_memory(address) == value {
+        break;
+      }
+    }
+  }
+  println!("Stopped after {} frames", emulator.frame_count() - start_frame);
+  emulator.stop_wav_recording()?;
+  if let (Some(path), Some(movie)) = (&options.record_movie, emulator.stop_movie_recording()) {
+    movie.save(path)?;
+  }
+
+  if let Some(path) = &options.png {
+    let screenshot = emulator.screenshot(&ScreenshotOptions {
+      overlay: !options.monochrome,
+      scale: options.scale,
+      background: None,
+    });
+    screenshot.save(path).map_err(Error::other)?;
+  }
+  if let Some(path) = &options.json {
+    let dump = Dump {
+      frame: emulator.frame_count(),
+      registers: emulator.registers(),
+      ram: emulator.ram(),
+    };
+    std::fs::write(path, serde_json::to_string_pretty(&dump)?)?;
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use emulate_8080::machine::PlayerKey;
+
+  #[test]
+  fn input_script() {
+    let events = parse_input_script("# Insert a coin and start\n10 press coin\n 12 release coin # comment\n\n5 press p1start\n").unwrap();
+    let events: Vec<_> = events.iter().map(|event| (event.frame, event.pressed, event.key)).collect();
+    assert_eq!(events, [
+      (5, true, PlayerKey::P1Start),
+      (10, true, PlayerKey::Coin),
+      (12, false, PlayerKey::Coin),
+    ]);
+
+    assert!(parse_input_script("10 push coin").is_err());
+    assert!(parse_input_script("10 press nothing").is_err());
+    assert!(parse_input_script("press coin").is_err());
+  }
+}
@@ -0,0 +1,54 @@
+// A device attached to the I/O ports, called synchronously by the IN and OUT instructions
+pub trait IoHandler {
+  fn input(&mut self, port: u8) -> u8;
+  fn output(&mut self, port: u8, value: u8);
+}
+
+// Everything the CPU can access outside of itself: memory, and the devices attached to the I/O ports
+// Each machine decides how its address space is laid out (ROM protection, mirroring, memory-mapped devices, banking)
+pub trait Bus: IoHandler {
+  // What a read of the address would give, without any of its side effects, so debuggers can look at memory
+  fn peek(&self, address: u16) -> u8;
+  // A read by the CPU, which a memory-mapped device can act on, such as by clearing a latch
+  fn read(&mut self, address: u16) -> u8 {
+    self.peek(address)
+  }
+  fn write(&mut self, address: u16, value: u8);
+}
+
+// A full 64 KiB of RAM with nothing attached to the I/O ports
+pub struct FlatMemory {
+  pub memory: [u8; 0x10000],
+}
+
+impl Default for FlatMemory {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl FlatMemory {
+  pub fn new() -> Self {
+    FlatMemory {
+      memory: [0; 0x10000],
+    }
+  }
+}
+
+impl Bus for FlatMemory {
+  fn peek(&self, address: u16) -> u8 {
+    self.memory[address as usize]
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    self.memory[address as usize] = value;
+  }
+}
+
+impl IoHandler for FlatMemory {
+  fn input(&mut self, _port: u8) -> u8 {
+    0
+  }
+
+  fn output(&mut self, _port: u8, _value: u8) {}
+}
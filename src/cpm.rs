@@ -0,0 +1,69 @@
+use crate::bus::FlatMemory;
+use crate::intel8080::Intel8080;
+
+// CP/M programs are loaded and started at the beginning of the Transient Program Area
+const TPA: u16 = 0x0100;
+const BDOS: u16 = 0x0005;
+// Programs find the top of usable memory by reading the address of the BDOS jump at 0x0006
+const BDOS_ADDRESS: u16 = 0xF000;
+
+// Just enough of CP/M to run programs that only use the BDOS console output functions, such as the CPU test ROMs
+pub struct Cpm {
+  cpu: Intel8080<FlatMemory>,
+  output: String,
+}
+
+impl Cpm {
+  pub fn new(program: &[u8]) -> Self {
+    let mut memory = FlatMemory::new();
+    memory.memory[TPA as usize..TPA as usize + program.len()].copy_from_slice(program);
+    // A RET at the BDOS entry point returns to the program after each call has been handled
+    memory.memory[BDOS as usize] = 0xC9;
+    memory.memory[BDOS as usize + 1] = (BDOS_ADDRESS & 0x00FF) as u8;
+    memory.memory[BDOS as usize + 2] = (BDOS_ADDRESS >> 8) as u8;
+    let mut cpu = Intel8080::new(memory);
+    cpu.set_pc(TPA);
+
+    Cpm {
+      cpu,
+      output: String::new(),
+    }
+  }
+
+  // Runs the program until it exits with a warm boot (jumping to 0x0000) or halts
+  pub fn run(&mut self) -> &str {
+    while self.cpu.pc() != 0x0000 && !self.cpu.is_halted() {
+      if self.cpu.pc() == BDOS {
+        self.bdos_call();
+      }
+      self.cpu.execute_next_instruction();
+    }
+
+    &self.output
+  }
+
+  fn bdos_call(&mut self) {
+    match self.cpu.bc() & 0x00FF {
+      2 => {
+        // C_WRITE: Print the character in E
+        self.output.push((self.cpu.de() & 0x00FF) as u8 as char);
+      }
+      9 => {
+        // C_WRITESTR: Print the string at DE, which ends with '$'
+        let mut address = self.cpu.de();
+        while self.cpu.bus().memory[address as usize] != b'$' {
+          self.output.push(self.cpu.bus().memory[address as usize] as char);
+          address = address.wrapping_add(1);
+        }
+      }
+      other => panic!("Unsupported BDOS function: {}", other),
+    }
+  }
+}
+
+// Convenience for running a program to completion and collecting everything it printed
+pub fn run_program(program: &[u8]) -> String {
+  let mut cpm = Cpm::new(program);
+  cpm.run();
+  cpm.output
+}
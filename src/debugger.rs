@@ -0,0 +1,403 @@
+use std::{fmt, str::FromStr};
+
+use crate::intel8080::Registers;
+
+// Anything a breakpoint condition can look at; the flags read as 0 or 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+  A,
+  B,
+  C,
+  D,
+  E,
+  H,
+  L,
+  BC,
+  DE,
+  HL,
+  SP,
+  PC,
+  S,
+  Z,
+  AC,
+  P,
+  CY,
+}
+
+const REGISTER_NAMES: [(Register, &str); 17] = [
+  (Register::A, "a"),
+  (Register::B, "b"),
+  (Register::C, "c"),
+  (Register::D, "d"),
+  (Register::E, "e"),
+  (Register::H, "h"),
+  (Register::L, "l"),
+  (Register::BC, "bc"),
+  (Register::DE, "de"),
+  (Register::HL, "hl"),
+  (Register::SP, "sp"),
+  (Register::PC, "pc"),
+  (Register::S, "s"),
+  (Register::Z, "z"),
+  (Register::AC, "ac"),
+  (Register::P, "p"),
+  (Register::CY, "cy"),
+];
+
+impl Register {
+  pub fn value(&self, registers: &Registers) -> u16 {
+    match self {
+      Register::A => registers.a as u16,
+      Register::B => registers.b as u16,
+      Register::C => registers.c as u16,
+      Register::D => registers.d as u16,
+      Register::E => registers.e as u16,
+      Register::H => registers.h as u16,
+      Register::L => registers.l as u16,
+      Register::BC => registers.bc(),
+      Register::DE => registers.de(),
+      Register::HL => registers.hl(),
+      Register::SP => registers.sp,
+      Register::PC => registers.pc,
+      Register::S => registers.flags.s as u16,
+      Register::Z => registers.flags.z as u16,
+      Register::AC => registers.flags.ac as u16,
+      Register::P => registers.flags.p as u16,
+      Register::CY => registers.flags.cy as u16,
+    }
+  }
+}
+
+impl FromStr for Register {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    let name = name.to_ascii_lowercase();
+    REGISTER_NAMES.iter()
+      .find(|(_, register_name)| *register_name == name)
+      .map(|&(register, _)| register)
+      .ok_or_else(|| format!("Unknown register {}", name))
+  }
+}
+
+impl fmt::Display for Register {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    let (_, name) = REGISTER_NAMES.iter().find(|(register, _)| register == self).expect("Every register has a name");
+    write!(formatter, "{}", name)
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+  Equal,
+  NotEqual,
+  Less,
+  LessOrEqual,
+  Greater,
+  GreaterOrEqual,
+}
+
+// Two-character operators come first so that "<=" isn't read as "<"
+const COMPARISON_OPERATORS: [(Comparison, &str); 6] = [
+  (Comparison::Equal, "=="),
+  (Comparison::NotEqual, "!="),
+  (Comparison::LessOrEqual, "<="),
+  (Comparison::GreaterOrEqual, ">="),
+  (Comparison::Less, "<"),
+  (Comparison::Greater, ">"),
+];
+
+// A test of a register against a value, such as "a == 3f" or "hl >= 2400"
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition {
+  pub register: Register,
+  pub comparison: Comparison,
+  pub value: u16,
+}
+
+impl Condition {
+  pub fn holds(&self, registers: &Registers) -> bool {
+    let register = self.register.value(registers);
+    match self.comparison {
+      Comparison::Equal => register == self.value,
+      Comparison::NotEqual => register != self.value,
+      Comparison::Less => register < self.value,
+      Comparison::LessOrEqual => register <= self.value,
+      Comparison::Greater => register > self.value,
+      Comparison::GreaterOrEqual => register >= self.value,
+    }
+  }
+}
+
+impl FromStr for Condition {
+  type Err = String;
+
+  fn from_str(text: &str) -> Result<Self, Self::Err> {
+    let (comparison, index, operator) = COMPARISON_OPERATORS.iter()
+      .filter_map(|&(comparison, operator)| text.find(operator).map(|index| (comparison, index, operator)))
+      .min_by_key(|&(_, index, _)| index)
+      .ok_or_else(|| format!("No comparison in {}", text))?;
+
+    Ok(Condition {
+      register: text[..index].trim().parse()?,
+      comparison,
+      value: parse_hex(text[index + operator.len()..].trim())?,
+    })
+  }
+}
+
+impl fmt::Display for Condition {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    let (_, operator) = COMPARISON_OPERATORS.iter().find(|(comparison, _)| *comparison == self.comparison).expect("Every comparison has an operator");
+    write!(formatter, "{} {} {:X}", self.register, operator, self.value)
+  }
+}
+
+// Numbers are hexadecimal, as they are everywhere else in the debugger, optionally written with a $ or 0x in front
+pub fn parse_hex(text: &str) -> Result<u16, String> {
+  let digits = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+  u16::from_str_radix(digits, 16).map_err(|_| format!("{} is not a hexadecimal number", text))
+}
+
+// Stops execution before the instruction at the address runs, if the condition holds when it gets there
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+  pub address: u16,
+  pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+  pub fn new(address: u16) -> Self {
+    Breakpoint {
+      address,
+      condition: None,
+    }
+  }
+
+  pub fn when(address: u16, condition: Condition) -> Self {
+    Breakpoint {
+      address,
+      condition: Some(condition),
+    }
+  }
+
+  pub fn hit(&self, registers: &Registers) -> bool {
+    self.address == registers.pc && self.condition.is_none_or(|condition| condition.holds(registers))
+  }
+}
+
+impl fmt::Display for Breakpoint {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match &self.condition {
+      Some(condition) => write!(formatter, "{:0>4X} if {}", self.address, condition),
+      None => write!(formatter, "{:0>4X}", self.address),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+  Read,
+  Write,
+  ReadWrite,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchAction {
+  // Stop after the instruction that made the access
+  Break,
+  // Keep a record of the access and carry on
+  Log,
+}
+
+// Watches the instructions' accesses to a range of memory, from start to end inclusive
+// Instruction fetches don't count, and neither do reads and writes from outside the CPU
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+  pub start: u16,
+  pub end: u16,
+  pub access: Access,
+  pub action: WatchAction,
+}
+
+impl Watchpoint {
+  pub fn new(start: u16, end: u16, access: Access, action: WatchAction) -> Self {
+    Watchpoint {
+      start: start.min(end),
+      end: start.max(end),
+      access,
+      action,
+    }
+  }
+
+  fn watches(&self, address: u16, write: bool) -> bool {
+    let access = matches!((self.access, write), (Access::ReadWrite, _) | (Access::Write, true) | (Access::Read, false));
+
+    access && (self.start..=self.end).contains(&address)
+  }
+}
+
+impl fmt::Display for Watchpoint {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match self.start == self.end {
+      true => write!(formatter, "{:0>4X}", self.start)?,
+      false => write!(formatter, "{:0>4X}-{:0>4X}", self.start, self.end)?,
+    }
+    let access = match self.access {
+      Access::Read => "r",
+      Access::Write => "w",
+      Access::ReadWrite => "rw",
+    };
+    match self.action {
+      WatchAction::Break => write!(formatter, " {}", access),
+      WatchAction::Log => write!(formatter, " {} log", access),
+    }
+  }
+}
+
+// An access to watched memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+  pub address: u16,
+  pub value: u8,
+  pub write: bool,
+  // Where the instruction that made the access starts
+  pub pc: u16,
+}
+
+impl fmt::Display for WatchHit {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match self.write {
+      true => write!(formatter, "{:0>4X}: wrote {:0>4X} = {:0>2X}", self.pc, self.address, self.value),
+      false => write!(formatter, "{:0>4X}: read {:0>4X} = {:0>2X}", self.pc, self.address, self.value),
+    }
+  }
+}
+
+// Why execution stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakReason {
+  Breakpoint(Breakpoint),
+  Watchpoint(WatchHit),
+  // A step, or running to an address, finished
+  Step,
+}
+
+// Where a step or run-to is headed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Target {
+  // Before the next instruction, wherever that is
+  NextInstruction,
+  // At the address, but only once the stack is back to at least the given level so recursive calls don't count
+  Address { address: u16, stack_pointer: Option<u16> },
+  // Once the current subroutine has returned, which pops the return address and leaves the stack above this level. A POP
+  // also raises the stack, so only a return counts
+  Return { stack_pointer: u16 },
+}
+
+// The debugging state attached to a CPU, which is not part of save states
+#[derive(Default)]
+pub struct Debugger {
+  pub(crate) breakpoints: Vec<Breakpoint>,
+  pub(crate) target: Option<Target>,
+  // Set when execution carries on from a stop, so the instruction there runs instead of stopping again
+  pub(crate) resume_pc: Option<u16>,
+  pub(crate) watchpoints: Vec<Watchpoint>,
+  // Where the instruction being executed starts, for reporting accesses
+  pub(crate) instruction_pc: u16,
+  // Whether the last instruction was a RET, or a conditional return that was taken
+  pub(crate) returned: bool,
+  // The first access during the last instruction that should stop execution
+  pub(crate) watch_break: Option<WatchHit>,
+  pub(crate) watch_log: Vec<WatchHit>,
+}
+
+impl Debugger {
+  pub(crate) fn check(&mut self, registers: &Registers) -> Option<BreakReason> {
+    let resuming = self.resume_pc.take() == Some(registers.pc);
+    if let Some(hit) = self.watch_break.take() {
+      return Some(BreakReason::Watchpoint(hit));
+    }
+    if resuming {
+      return None;
+    }
+
+    let reached = match self.target {
+      Some(Target::NextInstruction) => true,
+      Some(Target::Address { address, stack_pointer }) => {
+        address == registers.pc && stack_pointer.is_none_or(|stack_pointer| registers.sp >= stack_pointer)
+      }
+      Some(Target::Return { stack_pointer }) => self.returned && registers.sp > stack_pointer,
+      None => false,
+    };
+    if reached {
+      self.target = None;
+      return Some(BreakReason::Step);
+    }
+
+    self.breakpoints.iter().find(|breakpoint| breakpoint.hit(registers)).map(|&breakpoint| BreakReason::Breakpoint(breakpoint))
+  }
+
+  pub(crate) fn memory_access(&mut self, address: u16, value: u8, write: bool) {
+    for watchpoint in self.watchpoints.iter().filter(|watchpoint| watchpoint.watches(address, write)) {
+      let hit = WatchHit {
+        address,
+        value,
+        write,
+        pc: self.instruction_pc,
+      };
+      match watchpoint.action {
+        WatchAction::Break => {
+          self.watch_break.get_or_insert(hit);
+        }
+        WatchAction::Log => self.watch_log.push(hit),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn conditions() {
+    let registers = Registers {
+      a: 0x3F,
+      h: 0x24,
+      l: 0x10,
+      flags: crate::intel8080::Flags { z: true, ..Default::default() },
+      ..Default::default()
+    };
+    for (text, holds) in [("a == 3f", true), ("A==$3F", true), ("a != 3f", false), ("hl >= 2400", true), ("hl < 0x2410", false), ("hl <= 2410", true), ("z == 1", true), ("cy > 0", false)] {
+      let condition: Condition = text.parse().unwrap();
+      assert_eq!(condition.holds(&registers), holds, "{}", text);
+    }
+    assert_eq!("hl>=2400".parse::<Condition>().unwrap().to_string(), "hl >= 2400");
+    assert!("q == 1".parse::<Condition>().is_err());
+    assert!("a 1".parse::<Condition>().is_err());
+    assert!("a == xyz".parse::<Condition>().is_err());
+  }
+
+  #[test]
+  fn watchpoints() {
+    let mut debugger = Debugger::default();
+    debugger.watchpoints.push(Watchpoint::new(0x20F9, 0x20F8, Access::Write, WatchAction::Log));
+    debugger.watchpoints.push(Watchpoint::new(0x2100, 0x2100, Access::ReadWrite, WatchAction::Break));
+    debugger.instruction_pc = 0x0A1C;
+    debugger.memory_access(0x20F8, 0x12, false);
+    debugger.memory_access(0x20F8, 0x34, true);
+    debugger.memory_access(0x20FA, 0x56, true);
+    assert_eq!(debugger.watch_log.iter().map(|hit| hit.to_string()).collect::<Vec<String>>(), ["0A1C: wrote 20F8 = 34"]);
+    assert_eq!(debugger.watchpoints[0].to_string(), "20F8-20F9 w log");
+    assert!(debugger.watch_break.is_none());
+
+    // Execution stops at the next instruction, even after resuming
+    debugger.resume_pc = Some(0x0A1F);
+    debugger.memory_access(0x2100, 0x78, false);
+    debugger.memory_access(0x2100, 0x79, true);
+    let registers = Registers { pc: 0x0A1F, ..Default::default() };
+    assert_eq!(debugger.check(&registers), Some(BreakReason::Watchpoint(WatchHit { address: 0x2100, value: 0x78, write: false, pc: 0x0A1C })));
+    assert_eq!(debugger.check(&registers), None);
+  }
+}
@@ -0,0 +1,305 @@
+use crate::audio::{SoundBoard, SAMPLE_RATE};
+
+// A software model of the Space Invaders sound board, which is built from discrete analog circuits rather than samples
+// Every sound follows the level of its port bit: it builds up while the bit is set and dies away once it is cleared,
+// the same way the capacitors on the real board charge and discharge
+
+// Port 3
+const UFO: u8 = 0x01;
+const SHOT: u8 = 0x02;
+const PLAYER_EXPLOSION: u8 = 0x04;
+const INVADER_EXPLOSION: u8 = 0x08;
+const EXTENDED_PLAY: u8 = 0x10;
+const AMPLIFIER_ENABLE: u8 = 0x20;
+// Port 5
+const FLEET: [u8; 4] = [0x01, 0x02, 0x04, 0x08];
+const UFO_HIT: u8 = 0x10;
+
+// The four notes of the fleet march, in Hz, from the highest to the lowest
+const FLEET_FREQUENCIES: [f32; 4] = [98.0, 87.0, 78.0, 73.0];
+
+// An RC circuit: charges towards 1 while on and discharges towards 0 while off
+struct Envelope {
+  level: f32,
+  attack: f32,
+  release: f32,
+}
+
+impl Envelope {
+  fn new(attack_seconds: f32, release_seconds: f32) -> Self {
+    Envelope {
+      level: 0.0,
+      attack: rc_coefficient(attack_seconds),
+      release: rc_coefficient(release_seconds),
+    }
+  }
+
+  fn next(&mut self, on: bool) -> f32 {
+    match on {
+      true => self.level += (1.0 - self.level) * self.attack,
+      false => self.level -= self.level * self.release,
+    }
+
+    self.level
+  }
+}
+
+// How far a capacitor moves towards its target each sample, for the given time constant
+fn rc_coefficient(seconds: f32) -> f32 {
+  1.0 - (-1.0 / (seconds * SAMPLE_RATE as f32)).exp()
+}
+
+#[derive(Default)]
+struct Oscillator {
+  phase: f32,
+}
+
+impl Oscillator {
+  fn advance(&mut self, frequency: f32) -> f32 {
+    self.phase = (self.phase + frequency / SAMPLE_RATE as f32).fract();
+    self.phase
+  }
+
+  fn square(&mut self, frequency: f32) -> f32 {
+    match self.advance(frequency) < 0.5 {
+      true => 1.0,
+      false => -1.0,
+    }
+  }
+
+  fn triangle(&mut self, frequency: f32) -> f32 {
+    let phase = self.advance(frequency);
+    match phase < 0.5 {
+      true => 4.0 * phase - 1.0,
+      false => 3.0 - 4.0 * phase,
+    }
+  }
+}
+
+// White noise from a linear feedback shift register, smoothed by a low-pass filter
+struct Noise {
+  register: u32,
+  filtered: f32,
+  cutoff: f32,
+}
+
+impl Noise {
+  fn new(seed: u32, cutoff_seconds: f32) -> Self {
+    Noise {
+      register: seed,
+      filtered: 0.0,
+      cutoff: rc_coefficient(cutoff_seconds),
+    }
+  }
+
+  fn next(&mut self) -> f32 {
+    // 17-bit maximal length sequence, like the noise generators of the era
+    let bit = (self.register ^ (self.register >> 3)) & 1;
+    self.register = (self.register >> 1) | (bit << 16);
+    let white = match self.register & 1 {
+      0 => -1.0,
+      _ => 1.0,
+    };
+    self.filtered += (white - self.filtered) * self.cutoff;
+
+    self.filtered
+  }
+}
+
+pub struct DiscreteSoundBoard {
+  port3: u8,
+  port5: u8,
+  amplifier: Envelope,
+  ufo: Envelope,
+  ufo_tone: Oscillator,
+  ufo_warble: Oscillator,
+  shot: Envelope,
+  // Samples since the shot bit was last set, for the falling pitch
+  shot_time: u32,
+  shot_tone: Oscillator,
+  shot_noise: Noise,
+  player_explosion: Envelope,
+  player_explosion_noise: Noise,
+  invader_explosion: Envelope,
+  invader_explosion_noise: Noise,
+  extended_play: Envelope,
+  extended_play_tone: Oscillator,
+  extended_play_chop: Oscillator,
+  fleet: [Envelope; 4],
+  fleet_tones: [Oscillator; 4],
+  ufo_hit: Envelope,
+  ufo_hit_tone: Oscillator,
+  ufo_hit_warble: Oscillator,
+}
+
+impl Default for DiscreteSoundBoard {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl DiscreteSoundBoard {
+  pub fn new() -> Self {
+    DiscreteSoundBoard {
+      port3: 0,
+      port5: 0,
+      amplifier: Envelope::new(0.01, 0.01),
+      ufo: Envelope::new(0.005, 0.02),
+      ufo_tone: Oscillator::default(),
+      ufo_warble: Oscillator::default(),
+      shot: Envelope::new(0.002, 0.05),
+      shot_time: 0,
+      shot_tone: Oscillator::default(),
+      shot_noise: Noise::new(0x1ACE1, 0.0001),
+      player_explosion: Envelope::new(0.002, 0.6),
+      player_explosion_noise: Noise::new(0x0BEEF, 0.0004),
+      invader_explosion: Envelope::new(0.001, 0.15),
+      invader_explosion_noise: Noise::new(0x12345, 0.0001),
+      extended_play: Envelope::new(0.005, 0.05),
+      extended_play_tone: Oscillator::default(),
+      extended_play_chop: Oscillator::default(),
+      fleet: [(); 4].map(|_| Envelope::new(0.002, 0.08)),
+      fleet_tones: Default::default(),
+      ufo_hit: Envelope::new(0.005, 0.3),
+      ufo_hit_tone: Oscillator::default(),
+      ufo_hit_warble: Oscillator::default(),
+    }
+  }
+}
+
+impl SoundBoard for DiscreteSoundBoard {
+  fn set_ports(&mut self, port3: u8, port5: u8) {
+    if port3 & SHOT != 0 && self.port3 & SHOT == 0 {
+      self.shot_time = 0;
+    }
+    self.port3 = port3;
+    self.port5 = port5;
+  }
+
+  fn restore(&mut self, port3: u8, port5: u8) {
+    // Everything follows the levels, so a restored state sounds just like it did when it was saved
+    *self = DiscreteSoundBoard {
+      port3,
+      port5,
+      ..Self::new()
+    };
+  }
+
+  fn next_sample(&mut self) -> f32 {
+    let port3 = self.port3;
+    let port5 = self.port5;
+    let mut sample = 0.0;
+
+    // A siren whose pitch rises and falls several times a second
+    let ufo = self.ufo.next(port3 & UFO != 0);
+    if ufo > 0.0001 {
+      let pitch = 550.0 + 150.0 * self.ufo_warble.triangle(6.0);
+      sample += 0.25 * ufo * self.ufo_tone.triangle(pitch);
+    }
+
+    // A falling tone with a hiss that lasts for as long as the shot is on screen
+    let shot = self.shot.next(port3 & SHOT != 0);
+    if shot > 0.0001 {
+      let seconds = self.shot_time as f32 / SAMPLE_RATE as f32;
+      let pitch = 300.0 + 1200.0 * (-10.0 * seconds).exp();
+      let fade = 0.4 + 0.6 * (-3.0 * seconds).exp();
+      sample += shot * fade * (0.15 * self.shot_tone.square(pitch) + 0.2 * self.shot_noise.next());
+      self.shot_time = self.shot_time.saturating_add(1);
+    }
+
+    // Low rumbling noise
+    let player_explosion = self.player_explosion.next(port3 & PLAYER_EXPLOSION != 0);
+    if player_explosion > 0.0001 {
+      sample += 0.9 * player_explosion * self.player_explosion_noise.next();
+    }
+
+    // A sharper burst of noise
+    let invader_explosion = self.invader_explosion.next(port3 & INVADER_EXPLOSION != 0);
+    if invader_explosion > 0.0001 {
+      sample += 0.6 * invader_explosion * self.invader_explosion_noise.next();
+    }
+
+    // A high chirping tone for the extra life
+    let extended_play = self.extended_play.next(port3 & EXTENDED_PLAY != 0);
+    if extended_play > 0.0001 {
+      let chop = (self.extended_play_chop.square(16.0) + 1.0) / 2.0;
+      sample += 0.2 * extended_play * chop * self.extended_play_tone.square(1200.0);
+    }
+
+    // The fleet march is one low note for each bit, so only one should be set at a time
+    for (index, (envelope, tone)) in self.fleet.iter_mut().zip(&mut self.fleet_tones).enumerate() {
+      let level = envelope.next(port5 & FLEET[index] != 0);
+      if level > 0.0001 {
+        sample += 0.5 * level * tone.triangle(FLEET_FREQUENCIES[index]);
+      }
+    }
+
+    // A fast warble
+    let ufo_hit = self.ufo_hit.next(port5 & UFO_HIT != 0);
+    if ufo_hit > 0.0001 {
+      let pitch = 250.0 + 120.0 * self.ufo_hit_warble.square(12.0);
+      sample += 0.3 * ufo_hit * self.ufo_hit_tone.square(pitch);
+    }
+
+    // Nothing is heard unless the game has turned on the amplifier
+    sample * self.amplifier.next(port3 & AMPLIFIER_ENABLE != 0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn loudness(board: &mut DiscreteSoundBoard, seconds: f32) -> f32 {
+    let count = (seconds * SAMPLE_RATE as f32) as usize;
+    let total: f32 = (0..count).map(|_| board.next_sample().powi(2)).sum();
+
+    (total / count as f32).sqrt()
+  }
+
+  #[test]
+  fn silent_without_the_amplifier() {
+    let mut board = DiscreteSoundBoard::new();
+    board.set_ports(UFO | SHOT | PLAYER_EXPLOSION, 0x1F);
+    assert_eq!(loudness(&mut board, 0.1), 0.0);
+
+    // The amplifier on its own makes no sound
+    let mut board = DiscreteSoundBoard::new();
+    board.set_ports(AMPLIFIER_ENABLE, 0x00);
+    assert_eq!(loudness(&mut board, 0.1), 0.0);
+  }
+
+  #[test]
+  fn sounds_follow_the_level_of_their_bit() {
+    for (port3, port5) in [(UFO, 0), (SHOT, 0), (PLAYER_EXPLOSION, 0), (INVADER_EXPLOSION, 0), (EXTENDED_PLAY, 0), (0, FLEET[0]), (0, FLEET[3]), (0, UFO_HIT)] {
+      let mut board = DiscreteSoundBoard::new();
+      board.set_ports(AMPLIFIER_ENABLE | port3, port5);
+      let on = loudness(&mut board, 0.05);
+      // Still going for as long as the bit is held
+      let held = loudness(&mut board, 0.05);
+      board.set_ports(AMPLIFIER_ENABLE, 0x00);
+      loudness(&mut board, 4.0);
+      let off = loudness(&mut board, 0.05);
+      assert!(on > 0.02 && held > 0.02, "{:02X} {:02X}: {} {}", port3, port5, on, held);
+      assert!(off < 0.001, "{:02X} {:02X}: {}", port3, port5, off);
+    }
+  }
+
+  #[test]
+  fn everything_at_once() {
+    // The mixer clips anything too loud, but nothing should ever blow up
+    let mut board = DiscreteSoundBoard::new();
+    board.set_ports(0xFF, 0xFF);
+    assert!((0..SAMPLE_RATE).all(|_| board.next_sample().is_finite()));
+    assert!(loudness(&mut board, 0.1) > 0.1);
+  }
+
+  #[test]
+  fn restore_follows_the_levels() {
+    let mut board = DiscreteSoundBoard::new();
+    board.restore(AMPLIFIER_ENABLE | UFO, 0x00);
+    assert!(loudness(&mut board, 0.05) > 0.02);
+    board.restore(AMPLIFIER_ENABLE, 0x00);
+    assert_eq!(loudness(&mut board, 0.05), 0.0);
+  }
+}
@@ -0,0 +1,569 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use crate::bus::Bus;
+use crate::debugger::{Access, BreakReason, Breakpoint, WatchAction, Watchpoint};
+use crate::intel8080::{Flags, Intel8080, Registers};
+use crate::machine::Machine;
+
+// A stub for the GDB remote serial protocol, so external debuggers can control the CPU over a socket
+// GDB has no 8080 target, so the registers are laid out like the first six of its Z80 target: AF, BC, DE, HL, SP and PC,
+// each 16 bits and little-endian
+
+const REGISTER_COUNT: usize = 6;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+// Ctrl-C from the debugger, sent outside of any packet
+const INTERRUPT: u8 = 0x03;
+
+// Everything the stub needs from whatever it is debugging
+pub trait GdbTarget {
+  fn registers(&self) -> Registers;
+  fn set_registers(&mut self, registers: &Registers);
+  fn read_byte(&self, address: u16) -> u8;
+  fn write_byte(&mut self, address: u16, value: u8);
+  fn add_breakpoint(&mut self, breakpoint: Breakpoint);
+  fn remove_breakpoint(&mut self, address: u16) -> bool;
+  fn add_watchpoint(&mut self, watchpoint: Watchpoint);
+  fn remove_watchpoint(&mut self, address: u16) -> bool;
+  fn step_into(&mut self);
+  fn resume(&mut self);
+  // Runs for a short while, giving the reason if the debugger stopped it
+  fn run_slice(&mut self) -> Option<BreakReason>;
+}
+
+// Instructions to run between checks for an interrupt from the debugger
+const INSTRUCTIONS_PER_SLICE: u32 = 10_000;
+
+impl<B: Bus> GdbTarget for Intel8080<B> {
+  fn registers(&self) -> Registers {
+    Intel8080::registers(self)
+  }
+
+  fn set_registers(&mut self, registers: &Registers) {
+    Intel8080::set_registers(self, registers);
+  }
+
+  fn read_byte(&self, address: u16) -> u8 {
+    self.bus().peek(address)
+  }
+
+  fn write_byte(&mut self, address: u16, value: u8) {
+    self.bus_mut().write(address, value);
+  }
+
+  fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+    Intel8080::add_breakpoint(self, breakpoint);
+  }
+
+  fn remove_breakpoint(&mut self, address: u16) -> bool {
+    Intel8080::remove_breakpoint(self, address)
+  }
+
+  fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+    Intel8080::add_watchpoint(self, watchpoint);
+  }
+
+  fn remove_watchpoint(&mut self, address: u16) -> bool {
+    Intel8080::remove_watchpoint(self, address)
+  }
+
+  fn step_into(&mut self) {
+    Intel8080::step_into(self);
+  }
+
+  fn resume(&mut self) {
+    Intel8080::resume(self);
+  }
+
+  fn run_slice(&mut self) -> Option<BreakReason> {
+    for _ in 0..INSTRUCTIONS_PER_SLICE {
+      if let Some(reason) = self.check_break() {
+        return Some(reason);
+      }
+      self.execute_next_instruction();
+    }
+
+    None
+  }
+}
+
+// The whole machine runs a frame at a time, so interrupts and the rest of the hardware carry on as normal
+impl GdbTarget for Machine {
+  fn registers(&self) -> Registers {
+    Machine::registers(self)
+  }
+
+  fn set_registers(&mut self, registers: &Registers) {
+    Machine::set_registers(self, registers);
+  }
+
+  fn read_byte(&self, address: u16) -> u8 {
+    self.read_memory(address)
+  }
+
+  fn write_byte(&mut self, address: u16, value: u8) {
+    self.write_memory(address, value);
+  }
+
+  fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+    Machine::add_breakpoint(self, breakpoint);
+  }
+
+  fn remove_breakpoint(&mut self, address: u16) -> bool {
+    Machine::remove_breakpoint(self, address)
+  }
+
+  fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+    Machine::add_watchpoint(self, watchpoint);
+  }
+
+  fn remove_watchpoint(&mut self, address: u16) -> bool {
+    Machine::remove_watchpoint(self, address)
+  }
+
+  fn step_into(&mut self) {
+    Machine::step_into(self);
+  }
+
+  fn resume(&mut self) {
+    Machine::resume(self);
+  }
+
+  fn run_slice(&mut self) -> Option<BreakReason> {
+    let reason = self.run_frame();
+    // The stub reports the stop itself
+    self.take_break();
+    reason
+  }
+}
+
+// Serves one debugger connection until it detaches, kills the session or disconnects
+pub fn serve(target: &mut impl GdbTarget, stream: TcpStream) -> std::io::Result<()> {
+  stream.set_nodelay(true)?;
+  let mut stub = Stub {
+    stream,
+    acknowledge: true,
+    last_stop: stop_reply(None),
+  };
+  while let Some(packet) = stub.receive()? {
+    match stub.handle(target, &packet)? {
+      Some(reply) => stub.send(&reply)?,
+      None => break,
+    }
+    // The reply to this is still acknowledged, and nothing after it
+    if packet == "QStartNoAckMode" {
+      stub.acknowledge = false;
+    }
+  }
+
+  Ok(())
+}
+
+struct Stub {
+  stream: TcpStream,
+  // Packets are acknowledged with + until the debugger asks for no-ack mode
+  acknowledge: bool,
+  last_stop: String,
+}
+
+impl Stub {
+  // The next packet's contents, or None once the connection is closed
+  fn receive(&mut self) -> std::io::Result<Option<String>> {
+    loop {
+      // Anything before the start of a packet is an acknowledgement, or an interrupt with nothing running to interrupt
+      match self.read_byte()? {
+        Some(b'$') => (),
+        Some(_) => continue,
+        None => return Ok(None),
+      }
+
+      let mut data = Vec::new();
+      loop {
+        match self.read_byte()? {
+          Some(b'#') => break,
+          Some(byte) => data.push(byte),
+          None => return Ok(None),
+        }
+      }
+      let checksum = match (self.read_byte()?, self.read_byte()?) {
+        (Some(high), Some(low)) => u8::from_str_radix(&String::from_utf8_lossy(&[high, low]), 16).ok(),
+        _ => return Ok(None),
+      };
+
+      if self.acknowledge {
+        match checksum == Some(checksum_of(&data)) {
+          true => self.stream.write_all(b"+")?,
+          false => {
+            self.stream.write_all(b"-")?;
+            continue;
+          }
+        }
+      }
+
+      return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+    }
+  }
+
+  fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+    let mut byte = [0];
+    match self.stream.read(&mut byte)? {
+      0 => Ok(None),
+      _ => Ok(Some(byte[0])),
+    }
+  }
+
+  fn send(&mut self, data: &str) -> std::io::Result<()> {
+    let packet = format!("${}#{:0>2x}", data, checksum_of(data.as_bytes()));
+    self.stream.write_all(packet.as_bytes())
+  }
+
+  // The reply to a packet, or None to end the session
+  fn handle(&mut self, target: &mut impl GdbTarget, packet: &str) -> std::io::Result<Option<String>> {
+    let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+    let reply = match command {
+      "?" => self.last_stop.clone(),
+      "g" => encode_registers(&target.registers()),
+      "G" => match decode_registers(arguments, target.registers()) {
+        Some(registers) => {
+          target.set_registers(&registers);
+          ok()
+        }
+        None => error(),
+      },
+      "p" => match parse_number(arguments).and_then(|register| read_register(&target.registers(), register)) {
+        Some(value) => hex(&value.to_le_bytes()),
+        None => error(),
+      },
+      "P" => {
+        let registers = arguments.split_once('=').and_then(|(register, value)| {
+          let value = decode_hex(value).filter(|bytes| bytes.len() == 2)?;
+          write_register(target.registers(), parse_number(register)?, u16::from_le_bytes([value[0], value[1]]))
+        });
+        match registers {
+          Some(registers) => {
+            target.set_registers(&registers);
+            ok()
+          }
+          None => error(),
+        }
+      }
+      "m" => match parse_range(arguments) {
+        Some((address, length)) => {
+          let bytes: Vec<u8> = (0..length).map(|offset| target.read_byte(address.wrapping_add(offset))).collect();
+          hex(&bytes)
+        }
+        None => error(),
+      },
+      "M" => {
+        let write = arguments.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, decode_hex(data)?)));
+        match write {
+          Some(((address, length), bytes)) if bytes.len() == length as usize => {
+            for (offset, &byte) in bytes.iter().enumerate() {
+              target.write_byte(address.wrapping_add(offset as u16), byte);
+            }
+            ok()
+          }
+          _ => error(),
+        }
+      }
+      "Z" | "z" => match parse_point(arguments) {
+        Some((kind, address, length)) => set_point(target, command == "Z", kind, address, length),
+        None => error(),
+      },
+      "s" | "c" => {
+        if let Some(address) = parse_number(arguments) {
+          let mut registers = target.registers();
+          registers.pc = address;
+          target.set_registers(&registers);
+        }
+        match command {
+          "s" => target.step_into(),
+          _ => target.resume(),
+        }
+        self.last_stop = self.run(target)?;
+        self.last_stop.clone()
+      }
+      "k" => return Ok(None),
+      "D" => {
+        self.send("OK")?;
+        return Ok(None);
+      }
+      "H" => ok(),
+      "q" => match arguments.split(':').next().unwrap_or("") {
+        "Supported" => "PacketSize=1000".to_string(),
+        "Attached" => "1".to_string(),
+        "C" => "QC1".to_string(),
+        "fThreadInfo" => "m1".to_string(),
+        "sThreadInfo" => "l".to_string(),
+        _ => String::new(),
+      },
+      "Q" => match arguments {
+        "StartNoAckMode" => ok(),
+        _ => String::new(),
+      },
+      // Anything else isn't supported, which an empty reply tells the debugger
+      _ => String::new(),
+    };
+
+    Ok(Some(reply))
+  }
+
+  // Runs until the target stops or the debugger interrupts it, giving the stop reply
+  fn run(&mut self, target: &mut impl GdbTarget) -> std::io::Result<String> {
+    self.stream.set_nonblocking(true)?;
+    let result = loop {
+      if let Some(reason) = target.run_slice() {
+        break Ok(stop_reply(Some(reason)));
+      }
+      let mut byte = [0];
+      match self.stream.read(&mut byte) {
+        Ok(0) => break Err(std::io::Error::new(ErrorKind::ConnectionAborted, "The debugger disconnected")),
+        Ok(_) if byte[0] == INTERRUPT => break Ok(format!("S{:0>2x}", SIGINT)),
+        Ok(_) => (),
+        Err(error) if error.kind() == ErrorKind::WouldBlock => (),
+        Err(error) => break Err(error),
+      }
+    };
+    self.stream.set_nonblocking(false)?;
+
+    result
+  }
+}
+
+fn ok() -> String {
+  "OK".to_string()
+}
+
+fn error() -> String {
+  "E01".to_string()
+}
+
+fn stop_reply(reason: Option<BreakReason>) -> String {
+  match reason {
+    Some(BreakReason::Watchpoint(hit)) => format!(
+      "T{:0>2x}{}:{:x};",
+      SIGTRAP,
+      match hit.write {
+        true => "watch",
+        false => "rwatch",
+      },
+      hit.address
+    ),
+    _ => format!("S{:0>2x}", SIGTRAP),
+  }
+}
+
+// Z0 and Z1 are breakpoints, and Z2, Z3 and Z4 watch writes, reads and both; there's no difference between software and
+// hardware breakpoints here since neither changes memory
+fn set_point(target: &mut impl GdbTarget, insert: bool, kind: u16, address: u16, length: u16) -> String {
+  let access = match kind {
+    0 | 1 => None,
+    2 => Some(Access::Write),
+    3 => Some(Access::Read),
+    4 => Some(Access::ReadWrite),
+    _ => return String::new(),
+  };
+  let end = address.wrapping_add(length.max(1) - 1);
+  match (insert, access) {
+    (true, None) => target.add_breakpoint(Breakpoint::new(address)),
+    (true, Some(access)) => target.add_watchpoint(Watchpoint::new(address, end, access, WatchAction::Break)),
+    (false, None) => {
+      target.remove_breakpoint(address);
+    }
+    (false, Some(_)) => {
+      target.remove_watchpoint(address);
+    }
+  }
+
+  ok()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+  data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:0>2x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+  if !text.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..text.len()).step_by(2).map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok()).collect()
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+  u16::from_str_radix(text, 16).ok()
+}
+
+// addr,length
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+  let (address, length) = text.split_once(',')?;
+  Some((parse_number(address)?, parse_number(length)?))
+}
+
+// type,addr,kind
+fn parse_point(text: &str) -> Option<(u16, u16, u16)> {
+  let mut parts = text.split(',').map(parse_number);
+  Some((parts.next()??, parts.next()??, parts.next()??))
+}
+
+fn read_register(registers: &Registers, number: u16) -> Option<u16> {
+  let value = |high: u8, low: u8| (high as u16) << 8 | low as u16;
+  Some(match number {
+    0 => value(registers.a, registers.flags.to_byte()),
+    1 => registers.bc(),
+    2 => registers.de(),
+    3 => registers.hl(),
+    4 => registers.sp,
+    5 => registers.pc,
+    _ => return None,
+  })
+}
+
+fn write_register(mut registers: Registers, number: u16, value: u16) -> Option<Registers> {
+  match number {
+    0 => {
+      registers.a = (value >> 8) as u8;
+      registers.flags = Flags::from_byte(value as u8);
+    }
+    1 => registers.set_bc(value),
+    2 => registers.set_de(value),
+    3 => registers.set_hl(value),
+    4 => registers.sp = value,
+    5 => registers.pc = value,
+    _ => return None,
+  }
+
+  Some(registers)
+}
+
+fn encode_registers(registers: &Registers) -> String {
+  (0..REGISTER_COUNT as u16)
+    .filter_map(|number| read_register(registers, number))
+    .map(|value| hex(&value.to_le_bytes()))
+    .collect()
+}
+
+fn decode_registers(text: &str, registers: Registers) -> Option<Registers> {
+  let bytes = decode_hex(text).filter(|bytes| bytes.len() == REGISTER_COUNT * 2)?;
+  bytes.chunks(2).enumerate().try_fold(registers, |registers, (number, value)| {
+    write_register(registers, number as u16, u16::from_le_bytes([value[0], value[1]]))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bus::FlatMemory;
+  use std::net::TcpListener;
+
+  // Just enough of a GDB client to drive the stub
+  struct Client {
+    stream: TcpStream,
+  }
+
+  impl Client {
+    fn command(&mut self, data: &str) -> String {
+      let packet = format!("${}#{:0>2x}", data, checksum_of(data.as_bytes()));
+      self.stream.write_all(packet.as_bytes()).unwrap();
+      self.reply()
+    }
+
+    fn reply(&mut self) -> String {
+      let mut reply = Vec::new();
+      let mut byte = [0];
+      loop {
+        self.stream.read_exact(&mut byte).unwrap();
+        match byte[0] {
+          b'+' if reply.is_empty() => (),
+          b'#' => break,
+          byte => reply.push(byte),
+        }
+      }
+      let mut checksum = [0; 2];
+      self.stream.read_exact(&mut checksum).unwrap();
+      assert_eq!(reply.remove(0), b'$');
+      assert_eq!(u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(), checksum_of(&reply));
+
+      String::from_utf8(reply).unwrap()
+    }
+  }
+
+  fn start(program: &'static [u8]) -> (Client, std::thread::JoinHandle<()>) {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+      let mut cpu = Intel8080::new(FlatMemory::new());
+      cpu.bus_mut().memory[..program.len()].copy_from_slice(program);
+      let (stream, _) = listener.accept().unwrap();
+      serve(&mut cpu, stream).unwrap();
+    });
+
+    (Client { stream: TcpStream::connect(address).unwrap() }, server)
+  }
+
+  #[test]
+  fn remote_debugging() {
+    let (mut client, server) = start(&[
+      0x31, 0x00, 0x01, // LXI SP, $0100
+      0x3E, 0x42, // MVI A, $42
+      0x32, 0x00, 0x02, // STA $0200
+      0x3C, // INR A
+      0xC3, 0x08, 0x00, // JMP $0008
+    ]);
+    assert_eq!(client.command("qSupported:multiprocess+"), "PacketSize=1000");
+    assert_eq!(client.command("?"), "S05");
+    assert_eq!(client.command("g"), "460000000000000000000000");
+
+    assert_eq!(client.command("Z0,5,1"), "OK");
+    assert_eq!(client.command("c"), "S05");
+    assert_eq!(client.command("p0"), "4642");
+    assert_eq!(client.command("p5"), "0500");
+    assert_eq!(client.command("s"), "S05");
+    assert_eq!(client.command("p5"), "0800");
+    assert_eq!(client.command("m200,2"), "4200");
+    assert_eq!(client.command("M200,2:1234"), "OK");
+    assert_eq!(client.command("m1ff,4"), "00123400");
+
+    // Go back and run the store again, this time stopping for the write instead
+    assert_eq!(client.command("z0,5,1"), "OK");
+    assert_eq!(client.command("Z2,200,1"), "OK");
+    assert_eq!(client.command("c5"), "T05watch:200;");
+    assert_eq!(client.command("p5"), "0800");
+    assert_eq!(client.command("z2,200,1"), "OK");
+
+    // Set every register at once
+    assert_eq!(client.command("G0101020203030404000109000"), "E01");
+    assert_eq!(client.command("G010102020303040400010900"), "OK");
+    assert_eq!(client.command("g"), "030102020303040400010900");
+
+    assert_eq!(client.command("vMustReplyEmpty"), "");
+    assert_eq!(client.command("D"), "OK");
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn interrupt_and_watchpoints() {
+    let (mut client, server) = start(&[
+      0x3E, 0x07, // MVI A, 7
+      0x32, 0x10, 0x00, // STA $0010
+      0xC3, 0x00, 0x00, // JMP $0000
+    ]);
+    assert_eq!(client.command("QStartNoAckMode"), "OK");
+    assert_eq!(client.command("Z2,10,1"), "OK");
+    assert_eq!(client.command("c"), "T05watch:10;");
+    assert_eq!(client.command("p5"), "0500");
+    assert_eq!(client.command("z2,10,1"), "OK");
+
+    // With nothing to stop it, it runs until the debugger interrupts it
+    client.stream.write_all(b"$c#63").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    client.stream.write_all(&[INTERRUPT]).unwrap();
+    assert_eq!(client.reply(), "S02");
+    client.stream.write_all(b"$k#6b").unwrap();
+    server.join().unwrap();
+  }
+}
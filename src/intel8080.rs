@@ -1,27 +1,21 @@
-#[allow(unused_imports)]
-use std::io::Write;
+use serde::{Deserialize, Serialize};
 
+use crate::bus::Bus;
+use crate::debugger::{BreakReason, Breakpoint, Debugger, Target, WatchHit, Watchpoint};
+
+#[derive(Serialize, Deserialize)]
 enum Sign {
   Positive,
   Negative,
 }
 
+#[derive(Serialize, Deserialize)]
 enum Parity {
   Even,
   Odd,
 }
 
-enum Register {
-  A,
-  B,
-  C,
-  D,
-  E,
-  H,
-  L,
-  M,
-}
-
+#[derive(Serialize, Deserialize)]
 struct ConditionCodes {
   z: bool,
   s: Sign,
@@ -30,14 +24,107 @@ struct ConditionCodes {
   ac: bool,
 }
 
-#[derive(Copy, Clone)]
-enum Interrupts {
+// EI only lets interrupts in after the instruction that follows it, so there is a state in between
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Interrupts {
+  #[default]
   Disabled,
   PreEnabled,
   Enabled,
 }
 
-pub struct Intel8080 {
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Flags {
+  pub s: bool,
+  pub z: bool,
+  pub ac: bool,
+  // Set when the result has even parity
+  pub p: bool,
+  pub cy: bool,
+}
+
+impl Flags {
+  // The flags are packed the same way the 8080 pushes them with PUSH PSW: S Z 0 AC 0 P 1 CY
+  pub fn to_byte(&self) -> u8 {
+    let mut flags = 0b0000_0010;
+    if self.s {
+      flags |= 0b1000_0000;
+    }
+    if self.z {
+      flags |= 0b0100_0000;
+    }
+    if self.ac {
+      flags |= 0b0001_0000;
+    }
+    if self.p {
+      flags |= 0b0000_0100;
+    }
+    if self.cy {
+      flags |= 0b0000_0001;
+    }
+
+    flags
+  }
+
+  pub fn from_byte(flags: u8) -> Self {
+    Flags {
+      s: flags & 0b1000_0000 != 0,
+      z: flags & 0b0100_0000 != 0,
+      ac: flags & 0b0001_0000 != 0,
+      p: flags & 0b0000_0100 != 0,
+      cy: flags & 0b0000_0001 != 0,
+    }
+  }
+}
+
+// A snapshot of everything in the CPU other than the bus
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registers {
+  pub a: u8,
+  pub b: u8,
+  pub c: u8,
+  pub d: u8,
+  pub e: u8,
+  pub h: u8,
+  pub l: u8,
+  pub sp: u16,
+  pub pc: u16,
+  pub flags: Flags,
+  pub interrupts: Interrupts,
+  pub halted: bool,
+}
+
+impl Registers {
+  pub fn bc(&self) -> u16 {
+    (self.b as u16) << 8 | self.c as u16
+  }
+
+  pub fn set_bc(&mut self, value: u16) {
+    self.b = (value >> 8) as u8;
+    self.c = (value & 0x00FF) as u8;
+  }
+
+  pub fn de(&self) -> u16 {
+    (self.d as u16) << 8 | self.e as u16
+  }
+
+  pub fn set_de(&mut self, value: u16) {
+    self.d = (value >> 8) as u8;
+    self.e = (value & 0x00FF) as u8;
+  }
+
+  pub fn hl(&self) -> u16 {
+    (self.h as u16) << 8 | self.l as u16
+  }
+
+  pub fn set_hl(&mut self, value: u16) {
+    self.h = (value >> 8) as u8;
+    self.l = (value & 0x00FF) as u8;
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Intel8080<B: Bus> {
   a: u8,
   b: u8,
   c: u8,
@@ -47,17 +134,24 @@ pub struct Intel8080 {
   l: u8,
   sp: u16,
   pc: u16,
-  pub memory: [u8; 0x10000],
+  bus: B,
   cc: ConditionCodes,
   interrupts: Interrupts,
   halted: bool,
-  has_output: bool,
-  output_port: u8,
-  pub input_ports: [u8; 256],
+  #[serde(skip)]
+  strict_callback: Option<Box<dyn FnMut(u16, u8)>>,
+  #[serde(skip)]
+  debugger: Debugger,
 }
 
-impl Intel8080 {
-  pub fn new() -> Self {
+impl<B: Bus + Default> Default for Intel8080<B> {
+  fn default() -> Self {
+    Self::new(B::default())
+  }
+}
+
+impl<B: Bus> Intel8080<B> {
+  pub fn new(bus: B) -> Self {
     Intel8080 {
       a: 0,
       b: 0,
@@ -68,7 +162,7 @@ impl Intel8080 {
       l: 0,
       sp: 0,
       pc: 0,
-      memory: [0; 0x10000],
+      bus,
       cc: ConditionCodes {
         z: true,
         s: Sign::Positive,
@@ -78,12 +172,289 @@ impl Intel8080 {
       },
       interrupts: Interrupts::Disabled,
       halted: false,
-      has_output: false,
-      output_port: 0,
-      input_ports: [0; 256],
+      strict_callback: None,
+      debugger: Debugger::default(),
+    }
+  }
+
+  pub fn bus(&self) -> &B {
+    &self.bus
+  }
+
+  pub fn bus_mut(&mut self) -> &mut B {
+    &mut self.bus
+  }
+
+  pub fn a(&self) -> u8 {
+    self.a
+  }
+
+  pub fn set_a(&mut self, value: u8) {
+    self.a = value;
+  }
+
+  pub fn b(&self) -> u8 {
+    self.b
+  }
+
+  pub fn set_b(&mut self, value: u8) {
+    self.b = value;
+  }
+
+  pub fn c(&self) -> u8 {
+    self.c
+  }
+
+  pub fn set_c(&mut self, value: u8) {
+    self.c = value;
+  }
+
+  pub fn d(&self) -> u8 {
+    self.d
+  }
+
+  pub fn set_d(&mut self, value: u8) {
+    self.d = value;
+  }
+
+  pub fn e(&self) -> u8 {
+    self.e
+  }
+
+  pub fn set_e(&mut self, value: u8) {
+    self.e = value;
+  }
+
+  pub fn h(&self) -> u8 {
+    self.h
+  }
+
+  pub fn set_h(&mut self, value: u8) {
+    self.h = value;
+  }
+
+  pub fn l(&self) -> u8 {
+    self.l
+  }
+
+  pub fn set_l(&mut self, value: u8) {
+    self.l = value;
+  }
+
+  pub fn bc(&self) -> u16 {
+    (self.b as u16) << 8 | self.c as u16
+  }
+
+  pub fn set_bc(&mut self, value: u16) {
+    self.b = (value >> 8) as u8;
+    self.c = (value & 0x00FF) as u8;
+  }
+
+  pub fn de(&self) -> u16 {
+    (self.d as u16) << 8 | self.e as u16
+  }
+
+  pub fn set_de(&mut self, value: u16) {
+    self.d = (value >> 8) as u8;
+    self.e = (value & 0x00FF) as u8;
+  }
+
+  pub fn hl(&self) -> u16 {
+    (self.h as u16) << 8 | self.l as u16
+  }
+
+  pub fn set_hl(&mut self, value: u16) {
+    self.h = (value >> 8) as u8;
+    self.l = (value & 0x00FF) as u8;
+  }
+
+  pub fn sp(&self) -> u16 {
+    self.sp
+  }
+
+  pub fn set_sp(&mut self, value: u16) {
+    self.sp = value;
+  }
+
+  pub fn pc(&self) -> u16 {
+    self.pc
+  }
+
+  pub fn set_pc(&mut self, value: u16) {
+    self.pc = value;
+  }
+
+  pub fn flags(&self) -> Flags {
+    Flags {
+      s: matches!(self.cc.s, Sign::Negative),
+      z: self.cc.z,
+      ac: self.cc.ac,
+      p: matches!(self.cc.p, Parity::Even),
+      cy: self.cc.cy,
+    }
+  }
+
+  pub fn set_flags(&mut self, flags: Flags) {
+    self.cc = ConditionCodes {
+      z: flags.z,
+      s: if flags.s { Sign::Negative } else { Sign::Positive },
+      p: if flags.p { Parity::Even } else { Parity::Odd },
+      cy: flags.cy,
+      ac: flags.ac,
+    };
+  }
+
+  // EI sets the interrupt enable flip-flop immediately, even though interrupts are only accepted after the next instruction
+  pub fn interrupts_enabled(&self) -> bool {
+    !matches!(self.interrupts, Interrupts::Disabled)
+  }
+
+  pub fn set_interrupts_enabled(&mut self, enabled: bool) {
+    self.interrupts = match enabled {
+      true => Interrupts::Enabled,
+      false => Interrupts::Disabled,
+    };
+  }
+
+  pub fn is_halted(&self) -> bool {
+    self.halted
+  }
+
+  pub fn set_halted(&mut self, halted: bool) {
+    self.halted = halted;
+  }
+
+  pub fn registers(&self) -> Registers {
+    Registers {
+      a: self.a,
+      b: self.b,
+      c: self.c,
+      d: self.d,
+      e: self.e,
+      h: self.h,
+      l: self.l,
+      sp: self.sp,
+      pc: self.pc,
+      flags: self.flags(),
+      interrupts: self.interrupts,
+      halted: self.halted,
     }
   }
 
+  pub fn set_registers(&mut self, registers: &Registers) {
+    self.a = registers.a;
+    self.b = registers.b;
+    self.c = registers.c;
+    self.d = registers.d;
+    self.e = registers.e;
+    self.h = registers.h;
+    self.l = registers.l;
+    self.sp = registers.sp;
+    self.pc = registers.pc;
+    self.set_flags(registers.flags);
+    self.interrupts = registers.interrupts;
+    self.halted = registers.halted;
+  }
+
+  // Undocumented opcodes are always executed the same way the hardware does; in strict mode, the callback is also given
+  // the address and value of each one so that their use can be reported
+  pub fn set_strict_mode(&mut self, callback: Option<Box<dyn FnMut(u16, u8)>>) {
+    self.strict_callback = callback;
+  }
+
+  pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+    self.debugger.breakpoints.push(breakpoint);
+  }
+
+  // Removes every breakpoint at the address, returning false if there were none
+  pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+    let count = self.debugger.breakpoints.len();
+    self.debugger.breakpoints.retain(|breakpoint| breakpoint.address != address);
+    self.debugger.breakpoints.len() != count
+  }
+
+  pub fn breakpoints(&self) -> &[Breakpoint] {
+    &self.debugger.breakpoints
+  }
+
+  pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+    self.debugger.watchpoints.push(watchpoint);
+  }
+
+  // Removes every watchpoint covering the address, returning false if there were none
+  pub fn remove_watchpoint(&mut self, address: u16) -> bool {
+    let count = self.debugger.watchpoints.len();
+    self.debugger.watchpoints.retain(|watchpoint| !(watchpoint.start..=watchpoint.end).contains(&address));
+    self.debugger.watchpoints.len() != count
+  }
+
+  pub fn watchpoints(&self) -> &[Watchpoint] {
+    &self.debugger.watchpoints
+  }
+
+  // The accesses made to memory watched by logging watchpoints since the last call
+  pub fn take_watch_log(&mut self) -> Vec<WatchHit> {
+    std::mem::take(&mut self.debugger.watch_log)
+  }
+
+  // Whoever is running the CPU calls this before each instruction, and stops if it gives a reason
+  pub fn check_break(&mut self) -> Option<BreakReason> {
+    let registers = self.registers();
+    self.debugger.check(&registers)
+  }
+
+  // Carries on from a stop without stopping again for a breakpoint at the current instruction
+  pub fn resume(&mut self) {
+    self.debugger.target = None;
+    self.debugger.resume_pc = Some(self.pc);
+  }
+
+  // Stops again after the current instruction
+  pub fn step_into(&mut self) {
+    self.resume();
+    self.debugger.target = Some(Target::NextInstruction);
+  }
+
+  // Like step_into, except a subroutine called by the current instruction runs to completion
+  pub fn step_over(&mut self) {
+    self.resume();
+    let length = match self.bus.peek(self.pc) {
+      // CALL, its undocumented duplicates, and the conditional calls
+      0xCD | 0xDD | 0xED | 0xFD | 0xC4 | 0xCC | 0xD4 | 0xDC | 0xE4 | 0xEC | 0xF4 | 0xFC => 3,
+      // RST
+      0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => 1,
+      _ => 0,
+    };
+    self.debugger.target = Some(match length {
+      0 => Target::NextInstruction,
+      _ => Target::Address {
+        address: self.pc.wrapping_add(length),
+        stack_pointer: Some(self.sp),
+      },
+    });
+  }
+
+  // Stops once the current subroutine returns
+  pub fn step_out(&mut self) {
+    self.resume();
+    self.debugger.target = Some(Target::Return { stack_pointer: self.sp });
+  }
+
+  pub fn run_to(&mut self, address: u16) {
+    self.resume();
+    self.debugger.target = Some(Target::Address {
+      address,
+      stack_pointer: None,
+    });
+  }
+
+  // The debugger and strict mode callback belong to whoever is running the CPU rather than to its state, so they stay
+  // put when a saved CPU takes this one's place
+  pub(crate) fn swap_debugger(&mut self, other: &mut Self) {
+    std::mem::swap(&mut self.debugger, &mut other.debugger);
+    std::mem::swap(&mut self.strict_callback, &mut other.strict_callback);
+  }
+
   fn add(&mut self, value: u8) {
     let (result, overflow) = self.a.overflowing_add(value);
     self.cc.z = result == 0;
@@ -114,7 +485,8 @@ impl Intel8080 {
     self.cc.s = get_sign(result);
     self.cc.p = get_parity(result);
     self.cc.cy = overflow;
-    self.cc.ac = (self.a & 0x10) ^ (value & 0x10) ^ (result & 0x10) == 0x10;
+    // Subtraction is done as an addition of the complement, and AC is the carry out of bit 3 of that addition
+    self.cc.ac = (self.a & 0x10) ^ (value & 0x10) ^ (result & 0x10) == 0;
     self.a = result;
   }
 
@@ -129,7 +501,7 @@ impl Intel8080 {
     self.cc.s = get_sign(self.a);
     self.cc.p = get_parity(self.a);
     self.cc.cy = (result & 0x100) == 0x100;
-    self.cc.ac = (before & 0x10) ^ (value & 0x10) ^ (self.a & 0x10) == 0x10;
+    self.cc.ac = (before & 0x10) ^ (value & 0x10) ^ (self.a & 0x10) == 0;
   }
 
   fn compare(&mut self, value: u8) {
@@ -138,70 +510,60 @@ impl Intel8080 {
     self.cc.s = get_sign(result);
     self.cc.p = get_parity(result);
     self.cc.cy = overflow;
-    self.cc.ac = (self.a & 0x10) ^ (value & 0x10) ^ (result & 0x10) == 0x10;
+    self.cc.ac = (self.a & 0x10) ^ (value & 0x10) ^ (result & 0x10) == 0;
   }
 
-  fn increment(&mut self, register: Register) {
-    let register = match register {
-      Register::A => &mut self.a,
-      Register::B => &mut self.b,
-      Register::C => &mut self.c,
-      Register::D => &mut self.d,
-      Register::E => &mut self.e,
-      Register::H => &mut self.h,
-      Register::L => &mut self.l,
-      Register::M => &mut self.memory[((self.h as u16) << 8 | self.l as u16) as usize],
-    };
-    let before = *register;
-    *register = register.wrapping_add(1);
-    self.cc.z = *register == 0;
-    self.cc.s = get_sign(*register);
-    self.cc.p = get_parity(*register);
-    self.cc.ac = (before & 0x10) ^ (*register & 0x10) == 0x10;
-  }
-
-  fn decrement(&mut self, register: Register) {
-    let register = match register {
-      Register::A => &mut self.a,
-      Register::B => &mut self.b,
-      Register::C => &mut self.c,
-      Register::D => &mut self.d,
-      Register::E => &mut self.e,
-      Register::H => &mut self.h,
-      Register::L => &mut self.l,
-      Register::M => &mut self.memory[((self.h as u16) << 8 | self.l as u16) as usize],
-    };
-    let before = *register;
-    *register = register.wrapping_sub(1);
-    self.cc.z = *register == 0;
-    self.cc.s = get_sign(*register);
-    self.cc.p = get_parity(*register);
-    self.cc.ac = (before & 0x10) ^ (*register & 0x10) == 0x10;
+  fn increment(&mut self, value: u8) -> u8 {
+    let result = value.wrapping_add(1);
+    self.cc.z = result == 0;
+    self.cc.s = get_sign(result);
+    self.cc.p = get_parity(result);
+    self.cc.ac = (value & 0x10) ^ (result & 0x10) == 0x10;
+    result
+  }
+
+  fn decrement(&mut self, value: u8) -> u8 {
+    let result = value.wrapping_sub(1);
+    self.cc.z = result == 0;
+    self.cc.s = get_sign(result);
+    self.cc.p = get_parity(result);
+    // DCR adds 0xFF, which carries out of bit 3 unless the low nibble was zero
+    self.cc.ac = value & 0x0F != 0;
+    result
   }
 
   fn push(&mut self, high: u8, low: u8) {
-    self.write_memory(self.sp - 1, high);
-    self.write_memory(self.sp - 2, low);
-    self.sp -= 2;
+    self.write_memory(self.sp.wrapping_sub(1), high);
+    self.write_memory(self.sp.wrapping_sub(2), low);
+    self.sp = self.sp.wrapping_sub(2);
   }
 
   fn pop(&mut self) -> (u8, u8) {
-    let high = self.memory[self.sp as usize + 1];
-    let low = self.memory[self.sp as usize];
-    self.sp += 2;
+    let high = self.read_memory(self.sp.wrapping_add(1));
+    let low = self.read_memory(self.sp);
+    self.sp = self.sp.wrapping_add(2);
 
     (high, low)
   }
 
-  fn call(&mut self, address: &[u8]) {
-    let return_address = self.pc + 2; // Next instruction after this one
-    self.push((return_address >> 8) as u8, (return_address & 0x00FF) as u8);
-    self.pc = (address[1] as u16) << 8 | address[0] as u16;
+  fn call(&mut self, address: u16) {
+    // The PC is already past the operands, so it is the return address
+    self.push((self.pc >> 8) as u8, (self.pc & 0x00FF) as u8);
+    self.pc = address;
   }
 
   fn ret(&mut self) {
     let (high, low) = self.pop();
     self.pc = (high as u16) << 8 | low as u16;
+    self.debugger.returned = true;
+  }
+
+  fn and(&mut self, value: u8) {
+    // Unlike XRA and ORA, the 8080's AND sets AC from bit 3 of the operands ORed together
+    let ac = (self.a | value) & 0x08 == 0x08;
+    self.a &= value;
+    self.set_logic_flags();
+    self.cc.ac = ac;
   }
 
   fn set_logic_flags(&mut self) {
@@ -212,69 +574,74 @@ impl Intel8080 {
     self.cc.ac = false;
   }
 
-  fn read_from_hl(&self) -> u8 {
-    self.memory[((self.h as u16) << 8 | self.l as u16) as usize]
+  pub fn flags_byte(&self) -> u8 {
+    self.flags().to_byte()
+  }
+
+  pub fn set_flags_byte(&mut self, flags: u8) {
+    self.set_flags(Flags::from_byte(flags));
+  }
+
+  // Reads the byte at the PC and moves past it
+  fn fetch_byte(&mut self) -> u8 {
+    let value = self.bus.read(self.pc);
+    self.pc = self.pc.wrapping_add(1);
+    value
+  }
+
+  // Operands are stored low byte first
+  fn fetch_word(&mut self) -> u16 {
+    let low = self.fetch_byte();
+    let high = self.fetch_byte();
+    (high as u16) << 8 | low as u16
+  }
+
+  fn read_from_hl(&mut self) -> u8 {
+    self.read_memory((self.h as u16) << 8 | self.l as u16)
   }
 
   fn write_to_hl(&mut self, data: u8) {
     self.write_memory((self.h as u16) << 8 | self.l as u16, data);
   }
 
+  // Every data read and write made by an instruction (as opposed to instruction fetches) goes through these two, which
+  // is where the debugger watches them
+  fn read_memory(&mut self, address: u16) -> u8 {
+    let value = self.bus.read(address);
+    if !self.debugger.watchpoints.is_empty() {
+      self.debugger.memory_access(address, value, false);
+    }
+    value
+  }
+
   fn write_memory(&mut self, address: u16, data: u8) {
-    // These limits are specific to Space Invaders
-    #[cfg(not(feature = "cputest"))]
-    if address < 0x2000 {
-      println!("Attempted write to ROM {:0>4X}", address);
-      return;
-    } else if address >= 0x4000 {
-      print!("Attempted to write outside of Space Invaders RAM {:0>4X}: ", address);
-      self.disassemble_8080_op(self.pc - 1);
-      return;
+    if !self.debugger.watchpoints.is_empty() {
+      self.debugger.memory_access(address, data, true);
     }
+    self.bus.write(address, data);
+  }
 
-    self.memory[address as usize] = data;
+  fn restart(&mut self, number: u8) -> u8 {
+    self.push((self.pc >> 8) as u8, (self.pc & 0x00FF) as u8);
+    self.pc = 8 * (number & 0x07) as u16;
+    11
   }
 
-  pub fn generate_interrupt(&mut self, number: u8) {
+  pub fn generate_interrupt(&mut self, number: u8) -> u8 {
     match self.interrupts {
       Interrupts::Enabled => {
-        self.push((self.pc >> 8) as u8, (self.pc & 0x00FF) as u8);
-        self.pc = 8 * number as u16;
+        // The interrupt is acknowledged by executing the RST in place of the next instruction, so the PC is not advanced
         self.interrupts = Interrupts::Disabled;
-      },
-      _ => (),
-    }
-  }
-
-  #[cfg(feature = "cputest")]
-  fn special_print(&mut self) {
-    match self.c {
-      9 => {
-        // Message starts with ASCII sequence 0x0C 0x0D 0x0A (NPFF, CR, LF)
-        // Add 3 to the initial offset to skip them
-        let start = ((self.d as u16) << 8 | self.e as u16) as usize + 3;
-        let mut end = start;
-        // Message ends with '$'
-        while self.memory[end] != '$' as u8 {
-          end += 1;
-        }
-        let data = std::str::from_utf8(&self.memory[start..end]).unwrap();
-        eprint!("{}", data);
-        std::io::stderr().flush().unwrap();
-      }
-      2 => {
-        // Character
-        eprint!("{}", self.e as char);
-        std::io::stderr().flush().unwrap();
+        self.halted = false;
+        self.restart(number)
       }
-      other => panic!("Unknown special command: {}", other)
+      _ => 0,
     }
   }
 
-  pub fn get_output(&mut self) -> Option<(u8, u8)> {
-    match self.has_output {
-      true => Some((self.output_port, self.a)),
-      false => None,
+  fn undocumented_opcode(&mut self, opcode: u8) {
+    if let Some(callback) = &mut self.strict_callback {
+      callback(self.pc.wrapping_sub(1), opcode);
     }
   }
 
@@ -283,35 +650,30 @@ impl Intel8080 {
       return 0;
     }
 
-    #[cfg(feature = "cputest")]
-    if self.pc == 0x05 {
-      self.special_print();
-    }
-
     // If interrupts were set to be enabled last time, fully enable them this time around so they will be available after this instruction executes
     self.interrupts = match self.interrupts {
       Interrupts::PreEnabled => Interrupts::Enabled,
       other => other,
     };
-    self.has_output = false;
 
     #[cfg(feature = "printops")]
-    self.disassemble_8080_op(self.pc as usize);
+    self.disassemble_8080_op(self.pc);
 
-    let mut opcode = [0; 3];
-    opcode.copy_from_slice(&self.memory[self.pc as usize..self.pc as usize + 3]);
-    self.pc += 1;
+    self.debugger.instruction_pc = self.pc;
+    self.debugger.returned = false;
 
-    match opcode[0] {
+    // Operands are fetched by the instructions that have them, since a read can have side effects on the bus
+    let opcode = self.fetch_byte();
+
+    match opcode {
       0x00 => 4, // NOP
       0x01 => { // LXI B, D16
-        self.c = opcode[1];
-        self.b = opcode[2];
-        self.pc += 2;
+        let value = self.fetch_word();
+        self.set_bc(value);
         11
       }
       0x02 => { // STAX B
-        self.memory[((self.b as u16) << 8 | self.c as u16) as usize] = self.a;
+        self.write_memory((self.b as u16) << 8 | self.c as u16, self.a);
         7
       }
       0x03 => { // INX B
@@ -322,16 +684,15 @@ impl Intel8080 {
         5
       }
       0x04 => { // INR B
-        self.increment(Register::B);
+        self.b = self.increment(self.b);
         5
       }
       0x05 => { // DCR B
-        self.decrement(Register::B);
+        self.b = self.decrement(self.b);
         5
       }
       0x06 => { // MVI B, D8
-        self.b = opcode[1];
-        self.pc += 1;
+        self.b = self.fetch_byte();
         7
       }
       0x07 => { // RLC
@@ -339,7 +700,10 @@ impl Intel8080 {
         self.a = self.a.rotate_left(1);
         4
       }
-      // No 0x08
+      0x08 => { // *NOP
+        self.undocumented_opcode(opcode);
+        4
+      }
       0x09 => { // DAD B
         let hl = (self.h as u16) << 8 | self.l as u16;
         let bc = (self.b as u16) << 8 | self.c as u16;
@@ -350,7 +714,7 @@ impl Intel8080 {
         10
       }
       0x0a => { // LDAX B
-        self.a = self.memory[((self.b as u16) << 8 | self.c as u16) as usize];
+        self.a = self.read_memory((self.b as u16) << 8 | self.c as u16);
         7
       }
       0x0b => { // DCX B
@@ -361,16 +725,15 @@ impl Intel8080 {
         5
       }
       0x0c => { // INR C
-        self.increment(Register::C);
+        self.c = self.increment(self.c);
         5
       }
       0x0d => { // DCR C
-        self.decrement(Register::C);
+        self.c = self.decrement(self.c);
         5
       }
       0x0e => { // MVI C, D8
-        self.c = opcode[1];
-        self.pc += 1;
+        self.c = self.fetch_byte();
         7
       }
       0x0f => { // RRC
@@ -378,14 +741,17 @@ impl Intel8080 {
         self.a = self.a.rotate_right(1);
         4
       }
+      0x10 => { // *NOP
+        self.undocumented_opcode(opcode);
+        4
+      }
       0x11 => { // LXI D, D16
-        self.d = opcode[2];
-        self.e = opcode[1];
-        self.pc += 2;
+        let value = self.fetch_word();
+        self.set_de(value);
         10
       }
       0x12 => { // STAX D
-        self.memory[((self.d as u16) << 8 | self.e as u16) as usize] = self.a;
+        self.write_memory((self.d as u16) << 8 | self.e as u16, self.a);
         7
       }
       0x13 => { // INX D
@@ -396,16 +762,15 @@ impl Intel8080 {
         5
       }
       0x14 => { // INR D
-        self.increment(Register::D);
+        self.d = self.increment(self.d);
         5
       }
       0x15 => { // DCR D
-        self.decrement(Register::D);
+        self.d = self.decrement(self.d);
         5
       }
       0x16 => { // MVI D, D8
-        self.d = opcode[1];
-        self.pc += 1;
+        self.d = self.fetch_byte();
         7
       }
       0x17 => { // RAL
@@ -418,6 +783,10 @@ impl Intel8080 {
         };
         4
       }
+      0x18 => { // *NOP
+        self.undocumented_opcode(opcode);
+        4
+      }
       0x19 => { // DAD D
         let hl = (self.h as u16) << 8 | self.l as u16;
         let de = (self.d as u16) << 8 | self.e as u16;
@@ -428,7 +797,7 @@ impl Intel8080 {
         10
       }
       0x1a => { // LDAX D
-        self.a = self.memory[((self.d as u16) << 8 | self.e as u16) as usize];
+        self.a = self.read_memory((self.d as u16) << 8 | self.e as u16);
         7
       }
       0x1b => { // DCX D
@@ -439,16 +808,15 @@ impl Intel8080 {
         5
       }
       0x1c => { // INR E
-        self.increment(Register::E);
+        self.e = self.increment(self.e);
         5
       }
       0x1d => { // DCR E
-        self.decrement(Register::E);
+        self.e = self.decrement(self.e);
         5
       }
       0x1e => { // MVI E, D8
-        self.e = opcode[1];
-        self.pc += 1;
+        self.e = self.fetch_byte();
         7
       }
       0x1f => { // RAR
@@ -461,17 +829,19 @@ impl Intel8080 {
         };
         4
       }
+      0x20 => { // *NOP
+        self.undocumented_opcode(opcode);
+        4
+      }
       0x21 => { // LXI H, D16
-        self.h = opcode[2];
-        self.l = opcode[1];
-        self.pc += 2;
+        let value = self.fetch_word();
+        self.set_hl(value);
         10
       }
       0x22 => { // SHLD
-        let address = ((opcode[2] as u16) << 8 | opcode[1] as u16) as usize;
-        self.memory[address] = self.l;
-        self.memory[address + 1] = self.h;
-        self.pc += 2;
+        let address = self.fetch_word();
+        self.write_memory(address, self.l);
+        self.write_memory(address.wrapping_add(1), self.h);
         16
       }
       0x23 => { // INX H
@@ -482,31 +852,35 @@ impl Intel8080 {
         5
       }
       0x24 => { // INR H
-        self.increment(Register::H);
+        self.h = self.increment(self.h);
         5
       }
       0x25 => { // DCR H
-        self.decrement(Register::H);
+        self.h = self.decrement(self.h);
         5
       }
       0x26 => { // MVI H, D8
-        self.h = opcode[1];
-        self.pc += 1;
+        self.h = self.fetch_byte();
         7
       }
       0x27 => { // DAA
+        // Both corrections are decided from the value of A before either one is applied
+        let mut correction = 0;
+        let mut carry = self.cc.cy;
         if (self.a & 0x0F > 9) || self.cc.ac {
-          self.a = self.a.wrapping_add(6);
-          self.cc.ac = true;
-        } else {
-          self.cc.ac = false;
+          correction |= 0x06;
         }
-        if (self.a >> 4 > 9) || self.cc.cy {
-          self.a = self.a.wrapping_add(6 << 4);
-          self.cc.cy = true;
-        } else {
-          self.cc.cy = false;
+        if self.a > 0x99 || self.cc.cy {
+          correction |= 0x60;
+          carry = true;
         }
+        // The correction is added like ADD, except that the carry is only ever set, never cleared
+        self.add(correction);
+        self.cc.cy = carry;
+        4
+      }
+      0x28 => { // *NOP
+        self.undocumented_opcode(opcode);
         4
       }
       0x29 => { // DAD H
@@ -518,10 +892,9 @@ impl Intel8080 {
         10
       }
       0x2a => { // LHLD adr
-        let address = ((opcode[2] as u16) << 8 | opcode[1] as u16) as usize;
-        self.l = self.memory[address];
-        self.h = self.memory[address + 1];
-        self.pc += 2;
+        let address = self.fetch_word();
+        self.l = self.read_memory(address);
+        self.h = self.read_memory(address.wrapping_add(1));
         16
       }
       0x2b => { // DCX H
@@ -532,53 +905,63 @@ impl Intel8080 {
         5
       }
       0x2c => { // INR L
-        self.increment(Register::L);
+        self.l = self.increment(self.l);
         5
       }
       0x2d => { // DCR L
-        self.decrement(Register::L);
+        self.l = self.decrement(self.l);
         5
       }
       0x2e => { // MVI L, D8
-        self.l = opcode[1];
-        self.pc += 1;
+        self.l = self.fetch_byte();
         7
       }
       0x2f => { // CMA
         self.a = !self.a;
         4
       }
+      0x30 => { // *NOP
+        self.undocumented_opcode(opcode);
+        4
+      }
       0x31 => { // LXI SP, D16
-        self.sp = (opcode[2] as u16) << 8 | opcode[1] as u16;
-        self.pc += 2;
+        self.sp = self.fetch_word();
         10
       }
       0x32 => { // STA adr
-        self.memory[((opcode[2] as u16) << 8 | opcode[1] as u16) as usize] = self.a;
-        self.pc += 2;
+        let address = self.fetch_word();
+        self.write_memory(address, self.a);
         13
       }
       0x33 => { // INX SP
-        self.sp += 1;
+        self.sp = self.sp.wrapping_add(1);
         5
       }
       0x34 => { // INR M
-        self.increment(Register::M);
+        let value = self.read_from_hl();
+        let value = self.increment(value);
+        self.write_to_hl(value);
         10
       }
       0x35 => { // DCR M
-        self.decrement(Register::M);
+        let value = self.read_from_hl();
+        let value = self.decrement(value);
+        self.write_to_hl(value);
         10
       }
       0x36 => { // MVI M, D8
-        self.write_to_hl(opcode[1]);
-        self.pc += 1;
+        let value = self.fetch_byte();
+        self.write_to_hl(value);
         10
       }
       0x37 => { // STC
         self.cc.cy = true;
         4
       }
+      0x38 => { // *NOP
+        self.undocumented_opcode(opcode);
+        4
+      }
       0x39 => { // DAD SP
         let hl = (self.h as u16) << 8 | self.l as u16;
         let (result, overflow) = hl.overflowing_add(self.sp);
@@ -588,31 +971,33 @@ impl Intel8080 {
         10
       }
       0x3a => { // LDA adr
-        self.a = self.memory[((opcode[2] as u16) << 8 | opcode[1] as u16) as usize];
-        self.pc += 2;
+        let address = self.fetch_word();
+        self.a = self.read_memory(address);
         13
       }
       0x3b => { // DCX SP
-        self.sp -= 1;
+        self.sp = self.sp.wrapping_sub(1);
         5
       }
       0x3c => { // INR A
-        self.increment(Register::A);
+        self.a = self.increment(self.a);
         5
       }
       0x3d => { // DCR A
-        self.decrement(Register::A);
+        self.a = self.decrement(self.a);
         5
       }
       0x3e => { // MVI A, D8
-        self.a = opcode[1];
-        self.pc += 1;
+        self.a = self.fetch_byte();
         7
       }
       0x3f => { // CMC
         self.cc.cy = !self.cc.cy;
         4
       }
+      0x40 => { // MOV B, B
+        5
+      }
       0x41 => { // MOV B, C
         self.b = self.c;
         5
@@ -645,6 +1030,9 @@ impl Intel8080 {
         self.c = self.b;
         5
       }
+      0x49 => { // MOV C, C
+        5
+      }
       0x4a => { // MOV C, D
         self.c = self.d;
         5
@@ -677,6 +1065,9 @@ impl Intel8080 {
         self.d = self.c;
         5
       }
+      0x52 => { // MOV D, D
+        5
+      }
       0x53 => { // MOV D, E
         self.d = self.e;
         5
@@ -709,6 +1100,9 @@ impl Intel8080 {
         self.e = self.d;
         5
       }
+      0x5b => { // MOV E, E
+        5
+      }
       0x5c => { // MOV E, H
         self.e = self.h;
         5
@@ -741,6 +1135,9 @@ impl Intel8080 {
         self.h = self.e;
         5
       }
+      0x64 => { // MOV H, H
+        5
+      }
       0x65 => { // MOV H, L
         self.h = self.l;
         5
@@ -773,6 +1170,9 @@ impl Intel8080 {
         self.l = self.h;
         5
       }
+      0x6d => { // MOV L, L
+        5
+      }
       0x6e => { // MOV L, M
         self.l = self.read_from_hl();
         7
@@ -806,7 +1206,6 @@ impl Intel8080 {
         7
       }
       0x76 => { // HLT
-        println!("\n\nCPU Halted");
         self.halted = true;
         7
       }
@@ -842,6 +1241,9 @@ impl Intel8080 {
         self.a = self.read_from_hl();
         7
       }
+      0x7f => { // MOV A, A
+        5
+      }
       0x80 => { // ADD B
         self.add(self.b);
         4
@@ -867,7 +1269,8 @@ impl Intel8080 {
         4
       }
       0x86 => { // ADD M
-        self.add(self.read_from_hl());
+        let value = self.read_from_hl();
+        self.add(value);
         7
       }
       0x87 => { // ADD A
@@ -899,7 +1302,8 @@ impl Intel8080 {
         4
       }
       0x8e => { // ADC M
-        self.add_carry(self.read_from_hl());
+        let value = self.read_from_hl();
+        self.add_carry(value);
         7
       }
       0x8f => { // ADC A
@@ -931,7 +1335,8 @@ impl Intel8080 {
         4
       }
       0x96 => { // SUB M
-        self.subtract(self.read_from_hl());
+        let value = self.read_from_hl();
+        self.subtract(value);
         7
       }
       0x97 => { // SUB A
@@ -963,7 +1368,8 @@ impl Intel8080 {
         4
       }
       0x9e => { // SBB M
-        self.subtract_borrow(self.read_from_hl());
+        let value = self.read_from_hl();
+        self.subtract_borrow(value);
         7
       }
       0x9f => { // SBB A
@@ -972,43 +1378,36 @@ impl Intel8080 {
         4
       }
       0xa0 => { // ANA B
-        self.a &= self.b;
-        self.set_logic_flags();
+        self.and(self.b);
         4
       }
       0xa1 => { // ANA C
-        self.a &= self.c;
-        self.set_logic_flags();
+        self.and(self.c);
         4
       }
       0xa2 => { // ANA D
-        self.a &= self.d;
-        self.set_logic_flags();
+        self.and(self.d);
         4
       }
       0xa3 => { // ANA E
-        self.a &= self.e;
-        self.set_logic_flags();
+        self.and(self.e);
         4
       }
       0xa4 => { // ANA H
-        self.a &= self.h;
-        self.set_logic_flags();
+        self.and(self.h);
         4
       }
       0xa5 => { // ANA L
-        self.a &= self.l;
-        self.set_logic_flags();
+        self.and(self.l);
         4
       }
       0xa6 => { // ANA M
-        self.a &= self.read_from_hl();
-        self.set_logic_flags();
+        let value = self.read_from_hl();
+        self.and(value);
         7
       }
       0xa7 => { // ANA A
-        self.a &= self.a;
-        self.set_logic_flags();
+        self.and(self.a);
         4
       }
       0xa8 => { // XRA B
@@ -1116,7 +1515,8 @@ impl Intel8080 {
         4
       }
       0xbe => { // CMP M
-        self.compare(self.read_from_hl());
+        let value = self.read_from_hl();
+        self.compare(value);
         7
       }
       0xbf => { // CMP A
@@ -1139,26 +1539,24 @@ impl Intel8080 {
         10
       }
       0xc2 => { // JNZ adr
-        match self.cc.z {
-          false => self.pc = (opcode[2] as u16) << 8 | opcode[1] as u16,
-          true => self.pc += 2,
+        let address = self.fetch_word();
+        if !self.cc.z {
+          self.pc = address;
         }
         10
       }
       0xc3 => { // JMP adr
-        self.pc = (opcode[2] as u16) << 8 | opcode[1] as u16;
+        self.pc = self.fetch_word();
         10
       }
       0xc4 => { // CNZ adr
+        let address = self.fetch_word();
         match self.cc.z {
           false => {
-            self.call(&opcode[1..]);
+            self.call(address);
             17
           }
-          true => {
-            self.pc += 2;
-            11
-          }
+          true => 11,
         }
       }
       0xc5 => { // PUSH B
@@ -1166,10 +1564,13 @@ impl Intel8080 {
         11
       }
       0xc6 => { // ADI D8
-        self.add(opcode[1]);
-        self.pc += 1;
+        let value = self.fetch_byte();
+        self.add(value);
         7
       }
+      0xc7 => { // RST 0
+        self.restart(0)
+      }
       0xc8 => { // RZ
         match self.cc.z {
           true => {
@@ -1184,33 +1585,40 @@ impl Intel8080 {
         10
       }
       0xca => { // JZ adr
-        match self.cc.z {
-          true => self.pc = (opcode[2] as u16) << 8 | opcode[1] as u16,
-          false => self.pc += 2,
+        let address = self.fetch_word();
+        if self.cc.z {
+          self.pc = address;
         }
         10
       }
+      0xcb => { // *JMP adr
+        self.undocumented_opcode(opcode);
+        self.pc = self.fetch_word();
+        10
+      }
       0xcc => { // CZ adr
+        let address = self.fetch_word();
         match self.cc.z {
           true => {
-            self.call(&opcode[1..]);
+            self.call(address);
             17
           }
-          false => {
-            self.pc += 2;
-            11
-          }
+          false => 11,
         }
       }
       0xcd => { // CALL adr
-        self.call(&opcode[1..]);
+        let address = self.fetch_word();
+        self.call(address);
         17
       }
       0xce => { // ACI D8
-        self.add_carry(opcode[1]);
-        self.pc += 1;
+        let value = self.fetch_byte();
+        self.add_carry(value);
         7
       }
+      0xcf => { // RST 1
+        self.restart(1)
+      }
       0xd0 => { // RNC
         match self.cc.cy {
           false => {
@@ -1227,29 +1635,25 @@ impl Intel8080 {
         10
       }
       0xd2 => { // JNC adr
+        let address = self.fetch_word();
         if !self.cc.cy {
-          self.pc = (opcode[2] as u16) << 8 | opcode[1] as u16;
-        } else {
-          self.pc += 2;
+          self.pc = address;
         }
         10
       }
       0xd3 => { // OUT D8
-        self.output_port = opcode[1];
-        self.has_output = true;
-        self.pc += 1;
+        let port = self.fetch_byte();
+        self.bus.output(port, self.a);
         10
       }
       0xd4 => { // CNC adr
+        let address = self.fetch_word();
         match self.cc.cy {
           false => {
-            self.call(&opcode[1..]);
+            self.call(address);
             17
           }
-          true => {
-            self.pc += 2;
-            11
-          }
+          true => 11,
         }
       }
       0xd5 => { // PUSH D
@@ -1257,10 +1661,13 @@ impl Intel8080 {
         11
       }
       0xd6 => { // SUI D8
-        self.subtract(opcode[1]);
-        self.pc += 1;
+        let value = self.fetch_byte();
+        self.subtract(value);
         7
       }
+      0xd7 => { // RST 2
+        self.restart(2)
+      }
       0xd8 => { // RC
         match self.cc.cy {
           true => {
@@ -1270,36 +1677,47 @@ impl Intel8080 {
           false => 5,
         }
       }
+      0xd9 => { // *RET
+        self.undocumented_opcode(opcode);
+        self.ret();
+        10
+      }
       0xda => { // JC adr
+        let address = self.fetch_word();
         if self.cc.cy {
-          self.pc = (opcode[2] as u16) << 8 | opcode[1] as u16;
-        } else {
-          self.pc += 2;
+          self.pc = address;
         }
         10
       }
       0xdb => { // IN D8
-        self.a = self.input_ports[opcode[1] as usize];
-        self.pc += 1;
+        let port = self.fetch_byte();
+        self.a = self.bus.input(port);
         10
       }
       0xdc => { // CC adr
+        let address = self.fetch_word();
         match self.cc.cy {
           true => {
-            self.call(&opcode[1..]);
+            self.call(address);
             17
           }
-          false => {
-            self.pc += 2;
-            11
-          }
+          false => 11,
         }
       }
+      0xdd => { // *CALL adr
+        self.undocumented_opcode(opcode);
+        let address = self.fetch_word();
+        self.call(address);
+        17
+      }
       0xde => { // SBI D8
-        self.subtract_borrow(opcode[1]);
-        self.pc += 1;
+        let value = self.fetch_byte();
+        self.subtract_borrow(value);
         7
       }
+      0xdf => { // RST 3
+        self.restart(3)
+      }
       0xe0 => { // RPO
         match self.cc.p {
           Parity::Odd => {
@@ -1316,27 +1734,29 @@ impl Intel8080 {
         10
       }
       0xe2 => { // JPO adr
+        let address = self.fetch_word();
         match self.cc.p {
-          Parity::Odd => self.pc = (opcode[2] as u16) << 8 | opcode[1] as u16,
-          Parity::Even => self.pc += 2,
+          Parity::Odd => self.pc = address,
+          Parity::Even => (),
         }
         10
       }
       0xe3 => { // XTHL
-        std::mem::swap(&mut self.l, &mut self.memory[self.sp as usize]);
-        std::mem::swap(&mut self.h, &mut self.memory[self.sp as usize + 1]);
+        let (high, low) = (self.read_memory(self.sp.wrapping_add(1)), self.read_memory(self.sp));
+        self.write_memory(self.sp.wrapping_add(1), self.h);
+        self.write_memory(self.sp, self.l);
+        self.h = high;
+        self.l = low;
         18
       }
       0xe4 => { // CPO adr
+        let address = self.fetch_word();
         match self.cc.p {
           Parity::Odd => {
-            self.call(&opcode[1..]);
+            self.call(address);
             17
           }
-          Parity::Even => {
-            self.pc += 2;
-            11
-          }
+          Parity::Even => 11,
         }
       }
       0xe5 => { // PUSH H
@@ -1344,11 +1764,13 @@ impl Intel8080 {
         11
       }
       0xe6 => { // ANI D8
-        self.a &= opcode[1];
-        self.set_logic_flags();
-        self.pc += 1;
+        let value = self.fetch_byte();
+        self.and(value);
         7
       }
+      0xe7 => { // RST 4
+        self.restart(4)
+      }
       0xe8 => { // RPE
         match self.cc.p {
           Parity::Even => {
@@ -1363,11 +1785,12 @@ impl Intel8080 {
         5
       }
       0xea => { // JPE adr
+        let address = self.fetch_word();
         match self.cc.p {
           Parity::Even => {
-            self.pc = (opcode[2] as u16) << 8 | opcode[1] as u16;
+            self.pc = address;
           }
-          Parity::Odd => self.pc += 2
+          Parity::Odd => (),
         }
         10
       }
@@ -1377,23 +1800,30 @@ impl Intel8080 {
         4
       }
       0xec => { // CPE adr
+        let address = self.fetch_word();
         match self.cc.p {
           Parity::Even => {
-            self.call(&opcode[1..]);
+            self.call(address);
             17
           }
-          Parity::Odd => {
-            self.pc += 2;
-            11
-          }
+          Parity::Odd => 11,
         }
       }
+      0xed => { // *CALL adr
+        self.undocumented_opcode(opcode);
+        let address = self.fetch_word();
+        self.call(address);
+        17
+      }
       0xee => { // XRI D8
-        self.a ^= opcode[1];
+        let value = self.fetch_byte();
+        self.a ^= value;
         self.set_logic_flags();
-        self.pc += 1;
         7
       }
+      0xef => { // RST 5
+        self.restart(5)
+      }
       0xf0 => { // RP
         match self.cc.s {
           Sign::Positive => {
@@ -1406,23 +1836,14 @@ impl Intel8080 {
       0xf1 => { // POP PSW
         let (acc, flags) = self.pop();
         self.a = acc;
-        self.cc.z = flags & 0b1 == 0;
-        self.cc.s = match flags & 0b10 {
-          0 => Sign::Positive,
-          _ => Sign::Negative,
-        };
-        self.cc.p = match flags & 0b100 {
-          0 => Parity::Even,
-          _ => Parity::Odd,
-        };
-        self.cc.cy = flags & 0b1000 == 0b1000;
-        self.cc.ac = flags & 0b10000 == 0b10000;
+        self.set_flags_byte(flags);
         10
       }
       0xf2 => { // JP adr
+        let address = self.fetch_word();
         match self.cc.s {
-          Sign::Positive => self.pc = (opcode[2] as u16) << 8 | opcode[1] as u16,
-          Sign::Negative => self.pc += 2,
+          Sign::Positive => self.pc = address,
+          Sign::Negative => (),
         }
         10
       }
@@ -1432,48 +1853,28 @@ impl Intel8080 {
         4
       }
       0xf4 => { // CP adr
+        let address = self.fetch_word();
         match self.cc.s {
           Sign::Positive => {
-            self.call(&opcode[1..]);
+            self.call(address);
             17
           }
-          Sign::Negative => {
-            self.pc += 2;
-            11
-          }
+          Sign::Negative => 11,
         }
       }
       0xf5 => { // PUSH PSW
-        let mut flags = 0u8;
-        flags |= match self.cc.z {
-          true => 0,
-          false => 1,
-        };
-        flags |= match self.cc.s {
-          Sign::Negative => 0b10,
-          Sign::Positive => 0b00,
-        };
-        flags |= match self.cc.p {
-          Parity::Odd => 0b100,
-          Parity::Even => 0b000,
-        };
-        flags |= match self.cc.cy {
-          true => 0b1000,
-          false => 0b0000,
-        };
-        flags |= match self.cc.ac {
-          true => 0b10000,
-          false => 0b00000,
-        };
-        self.push(self.a, flags);
+        self.push(self.a, self.flags_byte());
         11
       }
       0xf6 => { // ORI D8
-        self.a |= opcode[1];
+        let value = self.fetch_byte();
+        self.a |= value;
         self.set_logic_flags();
-        self.pc += 1;
         7
       }
+      0xf7 => { // RST 6
+        self.restart(6)
+      }
       0xf8 => { // RM
         match self.cc.s {
           Sign::Negative => {
@@ -1488,9 +1889,10 @@ impl Intel8080 {
         5
       }
       0xfa => { // JM adr
+        let address = self.fetch_word();
         match self.cc.s {
-          Sign::Negative => self.pc = (opcode[2] as u16) << 8 | opcode[1] as u16,
-          Sign::Positive => self.pc += 2,
+          Sign::Negative => self.pc = address,
+          Sign::Positive => (),
         }
         10
       }
@@ -1500,1025 +1902,298 @@ impl Intel8080 {
         4
       }
       0xfc => { // CM adr
+        let address = self.fetch_word();
         match self.cc.s {
           Sign::Negative => {
-            self.call(&opcode[1..]);
+            self.call(address);
             17
           }
-          Sign::Positive => {
-            self.pc += 2;
-            11
-          }
+          Sign::Positive => 11,
         }
       }
+      0xfd => { // *CALL adr
+        self.undocumented_opcode(opcode);
+        let address = self.fetch_word();
+        self.call(address);
+        17
+      }
       0xfe => { // CPI D8
-        self.compare(opcode[1]);
-        self.pc += 1;
+        let value = self.fetch_byte();
+        self.compare(value);
         7
       }
-      other => {
-        //self.disassemble_8080_op(self.pc as usize - 1);
-        panic!("Unimplemented opcode: 0x{:0>2X}", other);
+      0xff => { // RST 7
+        self.restart(7)
       }
     }
   }
 
+  // Prints the instruction at the given address, returning its length
   pub fn disassemble_8080_op(&self, pc: u16) -> u16 {
-    let pc = pc as usize;
-    print!("{:0>4X} ", pc);
-    match self.memory[pc] {
-      0x00 => {
-        println!("NOP");
-        1
-      }
-      0x01 => {
-        println!("LXI    B, #${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0x02 => {
-        println!("STAX   B");
-        1
-      }
-      0x03 => {
-        println!("INX    B");
-        1
-      }
-      0x04 => {
-        println!("INR    B");
-        1
-      }
-      0x05 => {
-        println!("DCR    B");
-        1
-      }
-      0x06 => {
-        println!("MVI    B, #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0x07 => {
-        println!("RLC");
-        1
-      }
-      // No 0x08
-      0x09 => {
-        println!("DAD    B");
-        1
-      }
-      0x0a => {
-        println!("LDAX   B");
-        1
-      }
-      0x0b => {
-        println!("DCX    B");
-        1
-      }
-      0x0c => {
-        println!("INR    C");
-        1
-      }
-      0x0d => {
-        println!("DCR    C");
-        1
-      }
-      0x0e => {
-        println!("MVI    C, #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0x0f => {
-        println!("RRC");
-        1
-      }
-      // No 0x10
-      0x11 => {
-        println!("LXI    D, #${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0x12 => {
-        println!("STAX   D");
-        1
-      }
-      0x13 => {
-        println!("INX    D");
-        1
-      }
-      0x14 => {
-        println!("INR    D");
-        1
-      }
-      0x15 => {
-        println!("DCR    D");
-        1
-      }
-      0x16 => {
-        println!("MVI    D, #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0x17 => {
-        println!("RAL");
-        1
-      }
-      // No 0x18
-      0x19 => {
-        println!("DAD    D");
-        1
-      }
-      0x1a => {
-        println!("LDAX   D");
-        1
-      }
-      0x1b => {
-        println!("DCX    D");
-        1
-      }
-      0x1c => {
-        println!("INR    E");
-        1
-      }
-      0x1d => {
-        println!("DCR    E");
-        1
-      }
-      0x1e => {
-        println!("MVI    E, #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0x1f => {
-        println!("RAR");
-        1
-      }
-      // No 0x20
-      0x21 => {
-        println!("LXI    H, #${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0x22 => {
-        println!("SHLD   ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0x23 => {
-        println!("INX    H");
-        1
-      }
-      0x24 => {
-        println!("INR    H");
-        1
-      }
-      0x25 => {
-        println!("DCR    H");
-        1
-      }
-      0x26 => {
-        println!("MVI    H, #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0x27 => {
-        println!("DAA");
-        1
-      }
-      // No 0x28
-      0x29 => {
-        println!("DAD    H");
-        1
-      }
-      0x2a => {
-        println!("LHLD   ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0x2b => {
-        println!("DCX    H");
-        1
-      }
-      0x2c => {
-        println!("INR    L");
-        1
-      }
-      0x2d => {
-        println!("DCR    L");
-        1
-      }
-      0x2e => {
-        println!("MVI    L, #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0x2f => {
-        println!("CMA");
-        1
-      }
-      // No 0x30
-      0x31 => {
-        println!("LXI    SP, #${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0x32 => {
-        println!("STA    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0x33 => {
-        println!("INX    SP");
-        1
-      }
-      0x34 => {
-        println!("INR    M");
-        1
-      }
-      0x35 => {
-        println!("DCR    M");
-        1
-      }
-      0x36 => {
-        println!("MVI    M, #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0x37 => {
-        println!("STC");
-        1
-      }
-      // No 0x38
-      0x39 => {
-        println!("DAD    SP");
-        1
-      }
-      0x3a => {
-        println!("LDA    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0x3b => {
-        println!("DCX    SP");
-        1
-      }
-      0x3c => {
-        println!("INR    A");
-        1
-      }
-      0x3d => {
-        println!("DCR    A");
-        1
-      }
-      0x3e => {
-        println!("MVI    A, #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0x3f => {
-        println!("CMC");
-        1
-      }
-      0x40 => {
-        println!("MOV    B, B");
-        1
-      }
-      0x41 => {
-        println!("MOV    B, C");
-        1
-      }
-      0x42 => {
-        println!("MOV    B, D");
-        1
-      }
-      0x43 => {
-        println!("MOV    B, E");
-        1
-      }
-      0x44 => {
-        println!("MOV    B, H");
-        1
-      }
-      0x45 => {
-        println!("MOV    B, L");
-        1
-      }
-      0x46 => {
-        println!("MOV    B, M");
-        1
-      }
-      0x47 => {
-        println!("MOV    B, A");
-        1
-      }
-      0x48 => {
-        println!("MOV    C, B");
-        1
-      }
-      0x49 => {
-        println!("MOV    C, C");
-        1
-      }
-      0x4a => {
-        println!("MOV    C, D");
-        1
-      }
-      0x4b => {
-        println!("MOV    C, E");
-        1
-      }
-      0x4c => {
-        println!("MOV    C, H");
-        1
-      }
-      0x4d => {
-        println!("MOV    C, L");
-        1
-      }
-      0x4e => {
-        println!("MOV    C, M");
-        1
-      }
-      0x4f => {
-        println!("MOV    C, A");
-        1
-      }
-      0x50 => {
-        println!("MOV    D, B");
-        1
-      }
-      0x51 => {
-        println!("MOV    D, C");
-        1
-      }
-      0x52 => {
-        println!("MOV    D, D");
-        1
-      }
-      0x53 => {
-        println!("MOV    D, E");
-        1
-      }
-      0x54 => {
-        println!("MOV    D, H");
-        1
-      }
-      0x55 => {
-        println!("MOV    D, L");
-        1
-      }
-      0x56 => {
-        println!("MOV    D, M");
-        1
-      }
-      0x57 => {
-        println!("MOV    D, A");
-        1
-      }
-      0x58 => {
-        println!("MOV    E, B");
-        1
-      }
-      0x59 => {
-        println!("MOV    E, C");
-        1
-      }
-      0x5a => {
-        println!("MOV    E, D");
-        1
-      }
-      0x5b => {
-        println!("MOV    E, E");
-        1
-      }
-      0x5c => {
-        println!("MOV    E, H");
-        1
-      }
-      0x5d => {
-        println!("MOV    E, L");
-        1
-      }
-      0x5e => {
-        println!("MOV    E, M");
-        1
-      }
-      0x5f => {
-        println!("MOV    E, A");
-        1
-      }
-      0x60 => {
-        println!("MOV    H, B");
-        1
-      }
-      0x61 => {
-        println!("MOV    H, C");
-        1
-      }
-      0x62 => {
-        println!("MOV    H, D");
-        1
-      }
-      0x63 => {
-        println!("MOV    H, E");
-        1
-      }
-      0x64 => {
-        println!("MOV    H, H");
-        1
-      }
-      0x65 => {
-        println!("MOV    H, L");
-        1
-      }
-      0x66 => {
-        println!("MOV    H, M");
-        1
-      }
-      0x67 => {
-        println!("MOV    H, A");
-        1
-      }
-      0x68 => {
-        println!("MOV    L, B");
-        1
-      }
-      0x69 => {
-        println!("MOV    L, C");
-        1
-      }
-      0x6a => {
-        println!("MOV    L, D");
-        1
-      }
-      0x6b => {
-        println!("MOV    L, E");
-        1
-      }
-      0x6c => {
-        println!("MOV    L, H");
-        1
-      }
-      0x6d => {
-        println!("MOV    L, L");
-        1
-      }
-      0x6e => {
-        println!("MOV    L, M");
-        1
-      }
-      0x6f => {
-        println!("MOV    L, A");
-        1
-      }
-      0x70 => {
-        println!("MOV    M, B");
-        1
-      }
-      0x71 => {
-        println!("MOV    M, C");
-        1
-      }
-      0x72 => {
-        println!("MOV    M, D");
-        1
-      }
-      0x73 => {
-        println!("MOV    M, E");
-        1
-      }
-      0x74 => {
-        println!("MOV    M, H");
-        1
-      }
-      0x75 => {
-        println!("MOV    M, L");
-        1
-      }
-      0x76 => {
-        println!("HLT");
-        1
-      }
-      0x77 => {
-        println!("MOV    M, A");
-        1
-      }
-      0x78 => {
-        println!("MOV    A, B");
-        1
-      }
-      0x79 => {
-        println!("MOV    A, C");
-        1
-      }
-      0x7a => {
-        println!("MOV    A, D");
-        1
-      }
-      0x7b => {
-        println!("MOV    A, E");
-        1
-      }
-      0x7c => {
-        println!("MOV    A, H");
-        1
-      }
-      0x7d => {
-        println!("MOV    A, L");
-        1
-      }
-      0x7e => {
-        println!("MOV    A, M");
-        1
-      }
-      0x7f => {
-        println!("MOV    A, A");
-        1
-      }
-      0x80 => {
-        println!("ADD    B");
-        1
-      }
-      0x81 => {
-        println!("ADD    C");
-        1
-      }
-      0x82 => {
-        println!("ADD    D");
-        1
-      }
-      0x83 => {
-        println!("ADD    E");
-        1
-      }
-      0x84 => {
-        println!("ADD    H");
-        1
-      }
-      0x85 => {
-        println!("ADD    L");
-        1
-      }
-      0x86 => {
-        println!("ADD    M");
-        1
-      }
-      0x87 => {
-        println!("ADD    A");
-        1
-      }
-      0x88 => {
-        println!("ADC    B");
-        1
-      }
-      0x89 => {
-        println!("ADC    C");
-        1
-      }
-      0x8a => {
-        println!("ADC    D");
-        1
-      }
-      0x8b => {
-        println!("ADC    E");
-        1
-      }
-      0x8c => {
-        println!("ADC    H");
-        1
-      }
-      0x8d => {
-        println!("ADC    L");
-        1
-      }
-      0x8e => {
-        println!("ADC    M");
-        1
-      }
-      0x8f => {
-        println!("ADC    A");
-        1
-      }
-      0x90 => {
-        println!("SUB    B");
-        1
-      }
-      0x91 => {
-        println!("SUB    C");
-        1
-      }
-      0x92 => {
-        println!("SUB    D");
-        1
-      }
-      0x93 => {
-        println!("SUB    E");
-        1
-      }
-      0x94 => {
-        println!("SUB    H");
-        1
-      }
-      0x95 => {
-        println!("SUB    L");
-        1
-      }
-      0x96 => {
-        println!("SUB    M");
-        1
-      }
-      0x97 => {
-        println!("SUB    A");
-        1
-      }
-      0x98 => {
-        println!("SBB    B");
-        1
-      }
-      0x99 => {
-        println!("SBB    C");
-        1
-      }
-      0x9a => {
-        println!("SBB    D");
-        1
-      }
-      0x9b => {
-        println!("SBB    E");
-        1
-      }
-      0x9c => {
-        println!("SBB    H");
-        1
-      }
-      0x9d => {
-        println!("SBB    L");
-        1
-      }
-      0x9e => {
-        println!("SBB    M");
-        1
-      }
-      0x9f => {
-        println!("SBB    A");
-        1
-      }
-      0xa0 => {
-        println!("ANA    B");
-        1
-      }
-      0xa1 => {
-        println!("ANA    C");
-        1
-      }
-      0xa2 => {
-        println!("ANA    D");
-        1
-      }
-      0xa3 => {
-        println!("ANA    E");
-        1
-      }
-      0xa4 => {
-        println!("ANA    H");
-        1
-      }
-      0xa5 => {
-        println!("ANA    L");
-        1
-      }
-      0xa6 => {
-        println!("ANA    M");
-        1
-      }
-      0xa7 => {
-        println!("ANA    A");
-        1
-      }
-      0xa8 => {
-        println!("XRA    B");
-        1
-      }
-      0xa9 => {
-        println!("XRA    C");
-        1
-      }
-      0xaa => {
-        println!("XRA    D");
-        1
-      }
-      0xab => {
-        println!("XRA    E");
-        1
-      }
-      0xac => {
-        println!("XRA    H");
-        1
-      }
-      0xad => {
-        println!("XRA    L");
-        1
-      }
-      0xae => {
-        println!("XRA    M");
-        1
-      }
-      0xaf => {
-        println!("XRA    A");
-        1
-      }
-      0xb0 => {
-        println!("ORA    B");
-        1
-      }
-      0xb1 => {
-        println!("ORA    C");
-        1
-      }
-      0xb2 => {
-        println!("ORA    D");
-        1
-      }
-      0xb3 => {
-        println!("ORA    E");
-        1
-      }
-      0xb4 => {
-        println!("ORA    H");
-        1
-      }
-      0xb5 => {
-        println!("ORA    L");
-        1
-      }
-      0xb6 => {
-        println!("ORA    M");
-        1
-      }
-      0xb7 => {
-        println!("ORA    A");
-        1
-      }
-      0xb8 => {
-        println!("CMP    B");
-        1
-      }
-      0xb9 => {
-        println!("CMP    C");
-        1
-      }
-      0xba => {
-        println!("CMP    D");
-        1
-      }
-      0xbb => {
-        println!("CMP    E");
-        1
-      }
-      0xbc => {
-        println!("CMP    H");
-        1
-      }
-      0xbd => {
-        println!("CMP    L");
-        1
-      }
-      0xbe => {
-        println!("CMP    M");
-        1
-      }
-      0xbf => {
-        println!("CMP    A");
-        1
-      }
-      0xc0 => {
-        println!("RNZ");
-        1
-      }
-      0xc1 => {
-        println!("POP    B");
-        1
-      }
-      0xc2 => {
-        println!("JNZ    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xc3 => {
-        println!("JMP    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xc4 => {
-        println!("CNZ    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xc5 => {
-        println!("PUSH   B");
-        1
-      }
-      0xc6 => {
-        println!("ADI    #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0xc7 => {
-        println!("RST    0");
-        1
-      }
-      0xc8 => {
-        println!("RZ");
-        1
-      }
-      0xc9 => {
-        println!("RET");
-        1
-      }
-      0xca => {
-        println!("JZ     ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      // No 0xcb
-      0xcc => {
-        println!("CZ     ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xcd => {
-        println!("CALL   ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc +1]);
-        3
-      }
-      0xce => {
-        println!("ACI    #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0xcf => {
-        println!("RST    1");
-        1
-      }
-      0xd0 => {
-        println!("RNC");
-        1
-      }
-      0xd1 => {
-        println!("POP    D");
-        1
-      }
-      0xd2 => {
-        println!("JNC    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xd3 => {
-        println!("OUT    #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0xd4 => {
-        println!("CNC    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xd5 => {
-        println!("PUSH   D");
-        1
-      }
-      0xd6 => {
-        println!("SUI    #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0xd7 => {
-        println!("RST    2");
-        1
-      }
-      0xd8 => {
-        println!("RC");
-        1
-      }
-      // No 0xd9
-      0xda => {
-        println!("JC     ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xdb => {
-        println!("IN     #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0xdc => {
-        println!("CC     ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      // No 0xdd
-      0xde => {
-        println!("SBI    #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0xdf => {
-        println!("RST    3");
-        1
-      }
-      0xe0 => {
-        println!("RPO");
-        1
-      }
-      0xe1 => {
-        println!("POP    H");
-        1
-      }
-      0xe2 => {
-        println!("JPO    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xe3 => {
-        println!("XTHL");
-        1
-      }
-      0xe4 => {
-        println!("CPO    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xe5 => {
-        println!("PUSH   H");
-        1
-      }
-      0xe6 => {
-        println!("ANI    #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0xe7 => {
-        println!("RST    4");
-        1
-      }
-      0xe8 => {
-        println!("RPE");
-        1
-      }
-      0xe9 => {
-        println!("PCHL");
-        1
-      }
-      0xea => {
-        println!("JPE    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xeb => {
-        println!("XCHG");
-        1
-      }
-      0xec => {
-        println!("CPE    ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      // No 0xed
-      0xee => {
-        println!("XRI    #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0xef => {
-        println!("RST    5");
-        1
-      }
-      0xf0 => {
-        println!("RP");
-        1
-      }
-      0xf1 => {
-        println!("POP    PSW");
-        1
-      }
-      0xf2 => {
-        println!("JP     ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xf3 => {
-        println!("DI");
-        1
-      }
-      0xf4 => {
-        println!("CP     ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xf5 => {
-        println!("PUSH   PSW");
-        1
-      }
-      0xf6 => {
-        println!("ORI    #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0xf7 => {
-        println!("RST    6");
-        1
-      }
-      0xf8 => {
-        println!("RM");
-        1
-      }
-      0xf9 => {
-        println!("SPHL");
-        1
-      }
-      0xfa => {
-        println!("JM     ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      0xfb => {
-        println!("EI");
-        1
-      }
-      0xfc => {
-        println!("CM     ${:0>2X}{:0>2X}", self.memory[pc + 2], self.memory[pc + 1]);
-        3
-      }
-      // No 0xfd
-      0xfe => {
-        println!("CPI    #${:0>2X}", self.memory[pc + 1]);
-        2
-      }
-      0xff => {
-        println!("RST    7");
-        1
-      }
-      other => {
-        println!("**Missing opcode 0x{:0>2X}**", other);
-        1
-      }
+    let (instruction, length) = self.disassemble(pc);
+    println!("{:0>4X} {}", pc, instruction);
+    length
+  }
+
+  // The instruction at the given address, and its length
+  pub fn disassemble(&self, pc: u16) -> (String, u16) {
+    match self.bus.peek(pc) {
+      0x00 => ("NOP".to_string(), 1),
+      0x01 => (format!("LXI    B, #${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0x02 => ("STAX   B".to_string(), 1),
+      0x03 => ("INX    B".to_string(), 1),
+      0x04 => ("INR    B".to_string(), 1),
+      0x05 => ("DCR    B".to_string(), 1),
+      0x06 => (format!("MVI    B, #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0x07 => ("RLC".to_string(), 1),
+      0x08 => ("*NOP".to_string(), 1),
+      0x09 => ("DAD    B".to_string(), 1),
+      0x0a => ("LDAX   B".to_string(), 1),
+      0x0b => ("DCX    B".to_string(), 1),
+      0x0c => ("INR    C".to_string(), 1),
+      0x0d => ("DCR    C".to_string(), 1),
+      0x0e => (format!("MVI    C, #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0x0f => ("RRC".to_string(), 1),
+      0x10 => ("*NOP".to_string(), 1),
+      0x11 => (format!("LXI    D, #${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0x12 => ("STAX   D".to_string(), 1),
+      0x13 => ("INX    D".to_string(), 1),
+      0x14 => ("INR    D".to_string(), 1),
+      0x15 => ("DCR    D".to_string(), 1),
+      0x16 => (format!("MVI    D, #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0x17 => ("RAL".to_string(), 1),
+      0x18 => ("*NOP".to_string(), 1),
+      0x19 => ("DAD    D".to_string(), 1),
+      0x1a => ("LDAX   D".to_string(), 1),
+      0x1b => ("DCX    D".to_string(), 1),
+      0x1c => ("INR    E".to_string(), 1),
+      0x1d => ("DCR    E".to_string(), 1),
+      0x1e => (format!("MVI    E, #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0x1f => ("RAR".to_string(), 1),
+      0x20 => ("*NOP".to_string(), 1),
+      0x21 => (format!("LXI    H, #${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0x22 => (format!("SHLD   ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0x23 => ("INX    H".to_string(), 1),
+      0x24 => ("INR    H".to_string(), 1),
+      0x25 => ("DCR    H".to_string(), 1),
+      0x26 => (format!("MVI    H, #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0x27 => ("DAA".to_string(), 1),
+      0x28 => ("*NOP".to_string(), 1),
+      0x29 => ("DAD    H".to_string(), 1),
+      0x2a => (format!("LHLD   ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0x2b => ("DCX    H".to_string(), 1),
+      0x2c => ("INR    L".to_string(), 1),
+      0x2d => ("DCR    L".to_string(), 1),
+      0x2e => (format!("MVI    L, #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0x2f => ("CMA".to_string(), 1),
+      0x30 => ("*NOP".to_string(), 1),
+      0x31 => (format!("LXI    SP, #${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0x32 => (format!("STA    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0x33 => ("INX    SP".to_string(), 1),
+      0x34 => ("INR    M".to_string(), 1),
+      0x35 => ("DCR    M".to_string(), 1),
+      0x36 => (format!("MVI    M, #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0x37 => ("STC".to_string(), 1),
+      0x38 => ("*NOP".to_string(), 1),
+      0x39 => ("DAD    SP".to_string(), 1),
+      0x3a => (format!("LDA    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0x3b => ("DCX    SP".to_string(), 1),
+      0x3c => ("INR    A".to_string(), 1),
+      0x3d => ("DCR    A".to_string(), 1),
+      0x3e => (format!("MVI    A, #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0x3f => ("CMC".to_string(), 1),
+      0x40 => ("MOV    B, B".to_string(), 1),
+      0x41 => ("MOV    B, C".to_string(), 1),
+      0x42 => ("MOV    B, D".to_string(), 1),
+      0x43 => ("MOV    B, E".to_string(), 1),
+      0x44 => ("MOV    B, H".to_string(), 1),
+      0x45 => ("MOV    B, L".to_string(), 1),
+      0x46 => ("MOV    B, M".to_string(), 1),
+      0x47 => ("MOV    B, A".to_string(), 1),
+      0x48 => ("MOV    C, B".to_string(), 1),
+      0x49 => ("MOV    C, C".to_string(), 1),
+      0x4a => ("MOV    C, D".to_string(), 1),
+      0x4b => ("MOV    C, E".to_string(), 1),
+      0x4c => ("MOV    C, H".to_string(), 1),
+      0x4d => ("MOV    C, L".to_string(), 1),
+      0x4e => ("MOV    C, M".to_string(), 1),
+      0x4f => ("MOV    C, A".to_string(), 1),
+      0x50 => ("MOV    D, B".to_string(), 1),
+      0x51 => ("MOV    D, C".to_string(), 1),
+      0x52 => ("MOV    D, D".to_string(), 1),
+      0x53 => ("MOV    D, E".to_string(), 1),
+      0x54 => ("MOV    D, H".to_string(), 1),
+      0x55 => ("MOV    D, L".to_string(), 1),
+      0x56 => ("MOV    D, M".to_string(), 1),
+      0x57 => ("MOV    D, A".to_string(), 1),
+      0x58 => ("MOV    E, B".to_string(), 1),
+      0x59 => ("MOV    E, C".to_string(), 1),
+      0x5a => ("MOV    E, D".to_string(), 1),
+      0x5b => ("MOV    E, E".to_string(), 1),
+      0x5c => ("MOV    E, H".to_string(), 1),
+      0x5d => ("MOV    E, L".to_string(), 1),
+      0x5e => ("MOV    E, M".to_string(), 1),
+      0x5f => ("MOV    E, A".to_string(), 1),
+      0x60 => ("MOV    H, B".to_string(), 1),
+      0x61 => ("MOV    H, C".to_string(), 1),
+      0x62 => ("MOV    H, D".to_string(), 1),
+      0x63 => ("MOV    H, E".to_string(), 1),
+      0x64 => ("MOV    H, H".to_string(), 1),
+      0x65 => ("MOV    H, L".to_string(), 1),
+      0x66 => ("MOV    H, M".to_string(), 1),
+      0x67 => ("MOV    H, A".to_string(), 1),
+      0x68 => ("MOV    L, B".to_string(), 1),
+      0x69 => ("MOV    L, C".to_string(), 1),
+      0x6a => ("MOV    L, D".to_string(), 1),
+      0x6b => ("MOV    L, E".to_string(), 1),
+      0x6c => ("MOV    L, H".to_string(), 1),
+      0x6d => ("MOV    L, L".to_string(), 1),
+      0x6e => ("MOV    L, M".to_string(), 1),
+      0x6f => ("MOV    L, A".to_string(), 1),
+      0x70 => ("MOV    M, B".to_string(), 1),
+      0x71 => ("MOV    M, C".to_string(), 1),
+      0x72 => ("MOV    M, D".to_string(), 1),
+      0x73 => ("MOV    M, E".to_string(), 1),
+      0x74 => ("MOV    M, H".to_string(), 1),
+      0x75 => ("MOV    M, L".to_string(), 1),
+      0x76 => ("HLT".to_string(), 1),
+      0x77 => ("MOV    M, A".to_string(), 1),
+      0x78 => ("MOV    A, B".to_string(), 1),
+      0x79 => ("MOV    A, C".to_string(), 1),
+      0x7a => ("MOV    A, D".to_string(), 1),
+      0x7b => ("MOV    A, E".to_string(), 1),
+      0x7c => ("MOV    A, H".to_string(), 1),
+      0x7d => ("MOV    A, L".to_string(), 1),
+      0x7e => ("MOV    A, M".to_string(), 1),
+      0x7f => ("MOV    A, A".to_string(), 1),
+      0x80 => ("ADD    B".to_string(), 1),
+      0x81 => ("ADD    C".to_string(), 1),
+      0x82 => ("ADD    D".to_string(), 1),
+      0x83 => ("ADD    E".to_string(), 1),
+      0x84 => ("ADD    H".to_string(), 1),
+      0x85 => ("ADD    L".to_string(), 1),
+      0x86 => ("ADD    M".to_string(), 1),
+      0x87 => ("ADD    A".to_string(), 1),
+      0x88 => ("ADC    B".to_string(), 1),
+      0x89 => ("ADC    C".to_string(), 1),
+      0x8a => ("ADC    D".to_string(), 1),
+      0x8b => ("ADC    E".to_string(), 1),
+      0x8c => ("ADC    H".to_string(), 1),
+      0x8d => ("ADC    L".to_string(), 1),
+      0x8e => ("ADC    M".to_string(), 1),
+      0x8f => ("ADC    A".to_string(), 1),
+      0x90 => ("SUB    B".to_string(), 1),
+      0x91 => ("SUB    C".to_string(), 1),
+      0x92 => ("SUB    D".to_string(), 1),
+      0x93 => ("SUB    E".to_string(), 1),
+      0x94 => ("SUB    H".to_string(), 1),
+      0x95 => ("SUB    L".to_string(), 1),
+      0x96 => ("SUB    M".to_string(), 1),
+      0x97 => ("SUB    A".to_string(), 1),
+      0x98 => ("SBB    B".to_string(), 1),
+      0x99 => ("SBB    C".to_string(), 1),
+      0x9a => ("SBB    D".to_string(), 1),
+      0x9b => ("SBB    E".to_string(), 1),
+      0x9c => ("SBB    H".to_string(), 1),
+      0x9d => ("SBB    L".to_string(), 1),
+      0x9e => ("SBB    M".to_string(), 1),
+      0x9f => ("SBB    A".to_string(), 1),
+      0xa0 => ("ANA    B".to_string(), 1),
+      0xa1 => ("ANA    C".to_string(), 1),
+      0xa2 => ("ANA    D".to_string(), 1),
+      0xa3 => ("ANA    E".to_string(), 1),
+      0xa4 => ("ANA    H".to_string(), 1),
+      0xa5 => ("ANA    L".to_string(), 1),
+      0xa6 => ("ANA    M".to_string(), 1),
+      0xa7 => ("ANA    A".to_string(), 1),
+      0xa8 => ("XRA    B".to_string(), 1),
+      0xa9 => ("XRA    C".to_string(), 1),
+      0xaa => ("XRA    D".to_string(), 1),
+      0xab => ("XRA    E".to_string(), 1),
+      0xac => ("XRA    H".to_string(), 1),
+      0xad => ("XRA    L".to_string(), 1),
+      0xae => ("XRA    M".to_string(), 1),
+      0xaf => ("XRA    A".to_string(), 1),
+      0xb0 => ("ORA    B".to_string(), 1),
+      0xb1 => ("ORA    C".to_string(), 1),
+      0xb2 => ("ORA    D".to_string(), 1),
+      0xb3 => ("ORA    E".to_string(), 1),
+      0xb4 => ("ORA    H".to_string(), 1),
+      0xb5 => ("ORA    L".to_string(), 1),
+      0xb6 => ("ORA    M".to_string(), 1),
+      0xb7 => ("ORA    A".to_string(), 1),
+      0xb8 => ("CMP    B".to_string(), 1),
+      0xb9 => ("CMP    C".to_string(), 1),
+      0xba => ("CMP    D".to_string(), 1),
+      0xbb => ("CMP    E".to_string(), 1),
+      0xbc => ("CMP    H".to_string(), 1),
+      0xbd => ("CMP    L".to_string(), 1),
+      0xbe => ("CMP    M".to_string(), 1),
+      0xbf => ("CMP    A".to_string(), 1),
+      0xc0 => ("RNZ".to_string(), 1),
+      0xc1 => ("POP    B".to_string(), 1),
+      0xc2 => (format!("JNZ    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xc3 => (format!("JMP    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xc4 => (format!("CNZ    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xc5 => ("PUSH   B".to_string(), 1),
+      0xc6 => (format!("ADI    #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0xc7 => ("RST    0".to_string(), 1),
+      0xc8 => ("RZ".to_string(), 1),
+      0xc9 => ("RET".to_string(), 1),
+      0xca => (format!("JZ     ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xcb => (format!("*JMP   ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xcc => (format!("CZ     ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xcd => (format!("CALL   ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xce => (format!("ACI    #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0xcf => ("RST    1".to_string(), 1),
+      0xd0 => ("RNC".to_string(), 1),
+      0xd1 => ("POP    D".to_string(), 1),
+      0xd2 => (format!("JNC    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xd3 => (format!("OUT    #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0xd4 => (format!("CNC    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xd5 => ("PUSH   D".to_string(), 1),
+      0xd6 => (format!("SUI    #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0xd7 => ("RST    2".to_string(), 1),
+      0xd8 => ("RC".to_string(), 1),
+      0xd9 => ("*RET".to_string(), 1),
+      0xda => (format!("JC     ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xdb => (format!("IN     #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0xdc => (format!("CC     ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xdd => (format!("*CALL  ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xde => (format!("SBI    #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0xdf => ("RST    3".to_string(), 1),
+      0xe0 => ("RPO".to_string(), 1),
+      0xe1 => ("POP    H".to_string(), 1),
+      0xe2 => (format!("JPO    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xe3 => ("XTHL".to_string(), 1),
+      0xe4 => (format!("CPO    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xe5 => ("PUSH   H".to_string(), 1),
+      0xe6 => (format!("ANI    #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0xe7 => ("RST    4".to_string(), 1),
+      0xe8 => ("RPE".to_string(), 1),
+      0xe9 => ("PCHL".to_string(), 1),
+      0xea => (format!("JPE    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xeb => ("XCHG".to_string(), 1),
+      0xec => (format!("CPE    ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xed => (format!("*CALL  ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xee => (format!("XRI    #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0xef => ("RST    5".to_string(), 1),
+      0xf0 => ("RP".to_string(), 1),
+      0xf1 => ("POP    PSW".to_string(), 1),
+      0xf2 => (format!("JP     ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xf3 => ("DI".to_string(), 1),
+      0xf4 => (format!("CP     ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xf5 => ("PUSH   PSW".to_string(), 1),
+      0xf6 => (format!("ORI    #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0xf7 => ("RST    6".to_string(), 1),
+      0xf8 => ("RM".to_string(), 1),
+      0xf9 => ("SPHL".to_string(), 1),
+      0xfa => (format!("JM     ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xfb => ("EI".to_string(), 1),
+      0xfc => (format!("CM     ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xfd => (format!("*CALL  ${:0>2X}{:0>2X}", self.bus.peek(pc.wrapping_add(2)), self.bus.peek(pc.wrapping_add(1))), 3),
+      0xfe => (format!("CPI    #${:0>2X}", self.bus.peek(pc.wrapping_add(1))), 2),
+      0xff => ("RST    7".to_string(), 1),
     }
   }
 }
@@ -2537,3 +2212,545 @@ fn get_parity(byte: u8) -> Parity {
   value = ((0x6996 >> value) & 0x01) as u8;
   if value == 0 { Parity::Even } else { Parity::Odd }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bus::{FlatMemory, IoHandler};
+
+  // Logs every write and I/O access that reaches the bus
+  struct RecordingBus {
+    memory: FlatMemory,
+    writes: Vec<(u16, u8)>,
+    inputs: Vec<u8>,
+    outputs: Vec<(u8, u8)>,
+  }
+
+  impl Bus for RecordingBus {
+    fn peek(&self, address: u16) -> u8 {
+      self.memory.peek(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+      self.writes.push((address, value));
+      self.memory.write(address, value);
+    }
+  }
+
+  // Each IN returns the number of inputs read so far, and every OUT is logged
+  impl IoHandler for RecordingBus {
+    fn input(&mut self, port: u8) -> u8 {
+      self.inputs.push(port);
+      self.inputs.len() as u8
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+      self.outputs.push((port, value));
+    }
+  }
+
+  impl RecordingBus {
+    fn new(program: &[u8]) -> Self {
+      let mut memory = FlatMemory::new();
+      memory.memory[..program.len()].copy_from_slice(program);
+      RecordingBus {
+        memory,
+        writes: Vec::new(),
+        inputs: Vec::new(),
+        outputs: Vec::new(),
+      }
+    }
+  }
+
+  #[test]
+  fn io_handler_is_called_by_in_and_out() {
+    let program = [
+      0xDB, 0x01, // IN 1
+      0xD3, 0x02, // OUT 2
+      0xDB, 0x01, // IN 1
+      0xD3, 0x02, // OUT 2
+      0xD3, 0x03, // OUT 3
+    ];
+    let mut cpu = Intel8080::new(RecordingBus::new(&program));
+    for _ in 0..5 {
+      assert_eq!(cpu.execute_next_instruction(), 10);
+    }
+    assert_eq!(cpu.bus.inputs, vec![1, 1]);
+    assert_eq!(cpu.bus.outputs, vec![(2, 1), (2, 2), (3, 2)]);
+  }
+
+  #[test]
+  fn every_store_reaches_the_bus() {
+    let program = [
+      0x02, // STAX B
+      0x12, // STAX D
+      0x32, 0x00, 0x30, // STA $3000
+      0x22, 0x10, 0x30, // SHLD $3010
+      0x34, // INR M
+      0x35, // DCR M
+      0x36, 0x77, // MVI M, $77
+      0x70, // MOV M, B
+      0xE3, // XTHL
+      0xC5, // PUSH B
+      0xCD, 0x12, 0x00, // CALL $0012
+      0xFF, // RST 7
+    ];
+    let mut cpu = Intel8080::new(RecordingBus::new(&program));
+    cpu.a = 0xA5;
+    (cpu.b, cpu.c) = (0x20, 0x00);
+    (cpu.d, cpu.e) = (0x20, 0x01);
+    (cpu.h, cpu.l) = (0x20, 0x02);
+    cpu.sp = 0x4000;
+    cpu.bus.memory.memory[0x2002] = 0x41;
+    cpu.bus.memory.memory[0x4000] = 0x11;
+    cpu.bus.memory.memory[0x4001] = 0x22;
+    for _ in 0..12 {
+      cpu.execute_next_instruction();
+    }
+
+    assert_eq!(cpu.bus.writes, vec![
+      (0x2000, 0xA5), // STAX B
+      (0x2001, 0xA5), // STAX D
+      (0x3000, 0xA5), // STA
+      (0x3010, 0x02), // SHLD
+      (0x3011, 0x20),
+      (0x2002, 0x42), // INR M
+      (0x2002, 0x41), // DCR M
+      (0x2002, 0x77), // MVI M
+      (0x2002, 0x20), // MOV M, B
+      (0x4001, 0x20), // XTHL
+      (0x4000, 0x02),
+      (0x3FFF, 0x20), // PUSH B
+      (0x3FFE, 0x00),
+      (0x3FFD, 0x00), // CALL
+      (0x3FFC, 0x12),
+      (0x3FFB, 0x00), // RST 7
+      (0x3FFA, 0x13),
+    ]);
+    assert_eq!((cpu.h, cpu.l), (0x22, 0x11));
+    assert_eq!(cpu.pc, 0x0038);
+  }
+
+  // A memory-mapped latch at $4000 that clears itself once it has been read
+  struct LatchBus {
+    memory: FlatMemory,
+    latch: u8,
+  }
+
+  impl Bus for LatchBus {
+    fn peek(&self, address: u16) -> u8 {
+      match address {
+        0x4000 => self.latch,
+        _ => self.memory.peek(address),
+      }
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+      match address {
+        0x4000 => std::mem::take(&mut self.latch),
+        _ => self.memory.read(address),
+      }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+      self.memory.write(address, value);
+    }
+  }
+
+  impl IoHandler for LatchBus {
+    fn input(&mut self, _port: u8) -> u8 {
+      0
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+  }
+
+  #[test]
+  fn reads_can_have_side_effects() {
+    let mut memory = FlatMemory::new();
+    memory.memory[0..6].copy_from_slice(&[
+      0x3A, 0x00, 0x40, // LDA $4000
+      0x3A, 0x00, 0x40, // LDA $4000
+    ]);
+    let mut cpu = Intel8080::new(LatchBus { memory, latch: 0x5A });
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.a, 0x5A);
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.a, 0x00);
+  }
+
+  #[test]
+  fn only_operands_are_fetched() {
+    let mut memory = FlatMemory::new();
+    memory.memory[0x3FFA..0x4000].copy_from_slice(&[
+      0xC2, 0x00, 0x00, // JNZ $0000, which isn't taken
+      0x06, 0x07, // MVI B, 7
+      0x3C, // INR A
+    ]);
+    let mut cpu = Intel8080::new(LatchBus { memory, latch: 0x5A });
+    cpu.pc = 0x3FFA;
+    for _ in 0..3 {
+      cpu.execute_next_instruction();
+    }
+
+    // Running right up to the latch doesn't read it
+    assert_eq!((cpu.pc, cpu.a), (0x4000, 0x01));
+    assert_eq!(cpu.bus.latch, 0x5A);
+
+    // Neither does disassembling over it, or stepping over an instruction next to it
+    cpu.bus.memory.memory[0x3FFF] = 0x3A; // LDA
+    cpu.pc = 0x3FFF;
+    assert_eq!(cpu.disassemble(0x3FFF), ("LDA    $005A".to_string(), 3));
+    cpu.step_over();
+    assert_eq!(cpu.bus.latch, 0x5A);
+  }
+
+  #[test]
+  fn registers_round_trip() {
+    let mut cpu = Intel8080::new(FlatMemory::new());
+    let mut registers = Registers {
+      a: 0x01,
+      sp: 0xFFF0,
+      pc: 0x0100,
+      flags: Flags {
+        s: true,
+        z: false,
+        ac: true,
+        p: false,
+        cy: true,
+      },
+      interrupts: Interrupts::PreEnabled,
+      ..Default::default()
+    };
+    registers.set_bc(0x0203);
+    registers.set_de(0x0405);
+    registers.set_hl(0x0607);
+    cpu.set_registers(&registers);
+    assert_eq!(cpu.registers(), registers);
+    assert_eq!((cpu.b(), cpu.c(), cpu.d(), cpu.e(), cpu.h(), cpu.l()), (0x02, 0x03, 0x04, 0x05, 0x06, 0x07));
+    assert_eq!(cpu.flags_byte(), 0b1001_0011);
+
+    cpu.set_hl(0xBEEF);
+    cpu.set_a(0xAA);
+    assert_eq!((cpu.h(), cpu.l(), cpu.a()), (0xBE, 0xEF, 0xAA));
+    assert_eq!(cpu.registers().hl(), 0xBEEF);
+
+    // EI counts as enabling interrupts straight away
+    cpu.set_interrupts_enabled(false);
+    cpu.bus.memory[0x0100] = 0xFB; // EI
+    cpu.execute_next_instruction();
+    assert!(cpu.interrupts_enabled());
+
+    // But a round trip through the registers keeps the delay before an interrupt can be taken
+    let registers = cpu.registers();
+    assert_eq!(registers.interrupts, Interrupts::PreEnabled);
+    cpu.set_registers(&registers);
+    assert_eq!(cpu.generate_interrupt(1), 0);
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.registers().interrupts, Interrupts::Enabled);
+    assert_eq!(cpu.generate_interrupt(1), 11);
+  }
+
+  #[test]
+  fn flags_byte_round_trip() {
+    let mut cpu = Intel8080::new(FlatMemory::new());
+    for flags in 0..=255u8 {
+      cpu.set_flags_byte(flags);
+      // Bits 5 and 3 always read as 0 and bit 1 always reads as 1
+      assert_eq!(cpu.flags_byte(), (flags & 0b1101_0101) | 0b0000_0010, "flags {:0>8b}", flags);
+    }
+  }
+
+  #[test]
+  fn flags_byte_layout() {
+    let mut cpu = Intel8080::new(FlatMemory::new());
+    cpu.cc = ConditionCodes {
+      z: false,
+      s: Sign::Positive,
+      p: Parity::Odd,
+      cy: false,
+      ac: false,
+    };
+    assert_eq!(cpu.flags_byte(), 0b0000_0010);
+    cpu.cc.s = Sign::Negative;
+    assert_eq!(cpu.flags_byte(), 0b1000_0010);
+    cpu.cc.z = true;
+    assert_eq!(cpu.flags_byte(), 0b1100_0010);
+    cpu.cc.ac = true;
+    assert_eq!(cpu.flags_byte(), 0b1101_0010);
+    cpu.cc.p = Parity::Even;
+    assert_eq!(cpu.flags_byte(), 0b1101_0110);
+    cpu.cc.cy = true;
+    assert_eq!(cpu.flags_byte(), 0b1101_0111);
+  }
+
+  #[test]
+  fn daa_matches_hardware() {
+    let mut cpu = Intel8080::new(FlatMemory::new());
+    cpu.bus.memory[0] = 0x27; // DAA
+    let table = include_str!("../tests/data/daa.txt");
+    let mut cases = 0;
+    for line in table.lines().filter(|line| !line.starts_with('#')) {
+      let values: Vec<u8> = line
+        .split_whitespace()
+        .filter(|value| *value != "->")
+        .map(|value| u8::from_str_radix(value, 16).unwrap())
+        .collect();
+      let (a, flags, expected_a, expected_flags) = (values[0], values[1], values[2], values[3]);
+
+      cpu.pc = 0;
+      cpu.a = a;
+      cpu.set_flags_byte(flags);
+      assert_eq!(cpu.execute_next_instruction(), 4);
+      assert_eq!(
+        (cpu.a, cpu.flags_byte()),
+        (expected_a, expected_flags),
+        "DAA with A={:0>2X} F={:0>2X}", a, flags
+      );
+      cases += 1;
+    }
+    assert_eq!(cases, 256 * 4);
+  }
+
+  #[test]
+  fn auxiliary_carry() {
+    // Runs one immediate instruction or DCR A and returns the AC flag it leaves behind
+    fn ac_after(instruction: &[u8], a: u8, carry: bool) -> bool {
+      let mut cpu = Intel8080::new(FlatMemory::new());
+      cpu.bus.memory[..instruction.len()].copy_from_slice(instruction);
+      cpu.a = a;
+      cpu.cc.cy = carry;
+      cpu.cc.ac = !cpu.cc.ac;
+      cpu.execute_next_instruction();
+      cpu.cc.ac
+    }
+
+    // Subtractions report the carry out of bit 3 of A + ~value + 1, the inverse of a borrow
+    assert!(!ac_after(&[0xD6, 0x01], 0x10, false)); // SUI 1
+    assert!(ac_after(&[0xD6, 0x01], 0x11, false));
+    assert!(!ac_after(&[0xFE, 0x01], 0x10, false)); // CPI 1
+    assert!(ac_after(&[0xFE, 0x01], 0x11, false));
+    assert!(ac_after(&[0xDE, 0x00], 0x10, false)); // SBI 0
+    assert!(!ac_after(&[0xDE, 0x00], 0x10, true));
+    assert!(!ac_after(&[0x3D], 0x10, false)); // DCR A
+    assert!(ac_after(&[0x3D], 0x11, false));
+
+    // AND sets AC from bit 3 of A | value, while OR and XOR clear it
+    assert!(ac_after(&[0xE6, 0x00], 0x08, false)); // ANI 0
+    assert!(ac_after(&[0xE6, 0x08], 0x00, false));
+    assert!(!ac_after(&[0xE6, 0xF7], 0xF7, false));
+    assert!(!ac_after(&[0xF6, 0x08], 0x08, false)); // ORI 8
+    assert!(!ac_after(&[0xEE, 0x08], 0x08, false)); // XRI 8
+  }
+
+  #[test]
+  fn push_and_pop_psw() {
+    let mut cpu = Intel8080::new(FlatMemory::new());
+    cpu.bus.memory[0..6].copy_from_slice(&[
+      0xF5, // PUSH PSW
+      0xC1, // POP B
+      0xC5, // PUSH B
+      0xF1, // POP PSW
+      0xF5, // PUSH PSW
+      0xF1, // POP PSW
+    ]);
+    cpu.sp = 0x3000;
+    cpu.a = 0x42;
+    cpu.cc.z = false;
+    cpu.cc.s = Sign::Negative;
+    cpu.cc.p = Parity::Even;
+    cpu.cc.cy = true;
+    cpu.cc.ac = true;
+
+    assert_eq!(cpu.execute_next_instruction(), 11);
+    assert_eq!(cpu.sp, 0x2FFE);
+    assert_eq!(cpu.bus.memory[0x2FFF], 0x42);
+    assert_eq!(cpu.bus.memory[0x2FFE], 0b1001_0111);
+
+    // The flags come off the stack as the low byte of a register pair
+    cpu.execute_next_instruction();
+    assert_eq!((cpu.b, cpu.c), (0x42, 0b1001_0111));
+
+    // Setting every bit in the pushed byte should only set the real flags
+    cpu.c = 0xFF;
+    cpu.a = 0;
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.execute_next_instruction(), 10);
+    assert_eq!(cpu.a, 0x42);
+    assert!(cpu.cc.z && cpu.cc.cy && cpu.cc.ac);
+    assert!(matches!(cpu.cc.s, Sign::Negative));
+    assert!(matches!(cpu.cc.p, Parity::Even));
+
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.bus.memory[0x2FFE], 0b1101_0111);
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.flags_byte(), 0b1101_0111);
+    assert_eq!(cpu.sp, 0x3000);
+  }
+
+  // Runs until the debugger stops it, or gives up
+  fn run_until_break(cpu: &mut Intel8080<FlatMemory>) -> Option<BreakReason> {
+    for _ in 0..100 {
+      if let Some(reason) = cpu.check_break() {
+        return Some(reason);
+      }
+      cpu.execute_next_instruction();
+    }
+
+    None
+  }
+
+  #[test]
+  fn debugger_stepping() {
+    let mut cpu = Intel8080::new(FlatMemory::new());
+    let program = [
+      0x31, 0x00, 0x01, // LXI SP, $0100
+      0xCD, 0x10, 0x00, // CALL $0010
+      0x3E, 0x05, // MVI A, 5
+      0x76, // HLT
+    ];
+    cpu.bus.memory[..program.len()].copy_from_slice(&program);
+    cpu.bus.memory[0x10..0x13].copy_from_slice(&[
+      0x04, // INR B
+      0x04, // INR B
+      0xC9, // RET
+    ]);
+    let breakpoint = Breakpoint::when(0x0011, "b == 1".parse().unwrap());
+    cpu.add_breakpoint(breakpoint);
+
+    assert_eq!(run_until_break(&mut cpu), Some(BreakReason::Breakpoint(breakpoint)));
+    assert_eq!((cpu.pc, cpu.b), (0x0011, 1));
+    cpu.step_into();
+    assert_eq!(run_until_break(&mut cpu), Some(BreakReason::Step));
+    assert_eq!(cpu.pc, 0x0012);
+    cpu.step_out();
+    assert_eq!(run_until_break(&mut cpu), Some(BreakReason::Step));
+    assert_eq!((cpu.pc, cpu.sp), (0x0006, 0x0100));
+
+    // The condition no longer holds the second time through
+    cpu.pc = 0;
+    cpu.run_to(0x0003);
+    assert_eq!(run_until_break(&mut cpu), Some(BreakReason::Step));
+    assert_eq!(cpu.pc, 0x0003);
+    cpu.step_over();
+    assert_eq!(run_until_break(&mut cpu), Some(BreakReason::Step));
+    assert_eq!((cpu.pc, cpu.b), (0x0006, 4));
+    cpu.step_over();
+    assert_eq!(run_until_break(&mut cpu), Some(BreakReason::Step));
+    assert_eq!((cpu.pc, cpu.a), (0x0008, 5));
+
+    assert!(cpu.remove_breakpoint(0x0011));
+    assert!(!cpu.remove_breakpoint(0x0011));
+    assert!(cpu.breakpoints().is_empty());
+  }
+
+  #[test]
+  fn step_out_waits_for_a_return() {
+    let mut cpu = Intel8080::new(FlatMemory::new());
+    cpu.bus.memory[0x20..0x25].copy_from_slice(&[
+      0xCD, 0x30, 0x00, // CALL $0030
+      0xFF, // An inline argument the subroutine skips over
+      0x76, // HLT
+    ]);
+    // POP raises the stack above where it was on entry, and RZ isn't taken, so neither ends the subroutine
+    cpu.bus.memory[0x30..0x37].copy_from_slice(&[
+      0xE1, // POP H
+      0xF6, 0x01, // ORI 1
+      0xC8, // RZ
+      0x23, // INX H
+      0xE5, // PUSH H
+      0xC9, // RET
+    ]);
+    cpu.pc = 0x20;
+    cpu.sp = 0x0100;
+    cpu.add_breakpoint(Breakpoint::new(0x0030));
+    assert!(run_until_break(&mut cpu).is_some());
+    assert_eq!(cpu.pc, 0x0030);
+
+    cpu.step_out();
+    assert_eq!(run_until_break(&mut cpu), Some(BreakReason::Step));
+    assert_eq!((cpu.pc, cpu.sp), (0x0024, 0x0100));
+  }
+
+  #[test]
+  fn disassembly() {
+    let mut cpu = Intel8080::new(FlatMemory::new());
+    cpu.bus.memory[..4].copy_from_slice(&[0xCD, 0x34, 0x12, 0x00]);
+    assert_eq!(cpu.disassemble(0), ("CALL   $1234".to_string(), 3));
+    assert_eq!(cpu.disassemble(3), ("NOP".to_string(), 1));
+  }
+
+  #[test]
+  fn restarts() {
+    for number in 0..8u8 {
+      let mut cpu = Intel8080::new(FlatMemory::new());
+      cpu.bus.memory[0x1000] = 0xC7 | number << 3; // RST n
+      cpu.pc = 0x1000;
+      cpu.sp = 0x3000;
+      assert_eq!(cpu.execute_next_instruction(), 11, "RST {}", number);
+      assert_eq!(cpu.pc, 8 * number as u16);
+      assert_eq!(cpu.sp, 0x2FFE);
+      assert_eq!(cpu.bus.memory[0x2FFE..0x3000], [0x01, 0x10]);
+
+      // An interrupt runs the same RST, but returns to the instruction it interrupted
+      cpu.pc = 0x1234;
+      assert_eq!(cpu.generate_interrupt(number), 0);
+      assert_eq!((cpu.pc, cpu.sp), (0x1234, 0x2FFE));
+      cpu.interrupts = Interrupts::Enabled;
+      assert_eq!(cpu.generate_interrupt(number), 11);
+      assert_eq!(cpu.pc, 8 * number as u16);
+      assert_eq!(cpu.sp, 0x2FFC);
+      assert_eq!(cpu.bus.memory[0x2FFC..0x2FFE], [0x34, 0x12]);
+      assert!(matches!(cpu.interrupts, Interrupts::Disabled));
+    }
+  }
+
+  #[test]
+  fn undocumented_opcodes() {
+    let mut cpu = Intel8080::new(FlatMemory::new());
+    cpu.bus.memory[0x00..0x07].copy_from_slice(&[0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38]); // NOP
+    cpu.bus.memory[0x07..0x0A].copy_from_slice(&[0xCB, 0x10, 0x00]); // JMP $0010
+    cpu.bus.memory[0x10..0x13].copy_from_slice(&[0xDD, 0x20, 0x00]); // CALL $0020
+    cpu.bus.memory[0x13..0x16].copy_from_slice(&[0xED, 0x20, 0x00]); // CALL $0020
+    cpu.bus.memory[0x16..0x19].copy_from_slice(&[0xFD, 0x20, 0x00]); // CALL $0020
+    cpu.bus.memory[0x19] = 0x00; // NOP
+    cpu.bus.memory[0x20] = 0xD9; // RET
+    cpu.sp = 0x3000;
+    let reported = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let log = reported.clone();
+    cpu.set_strict_mode(Some(Box::new(move |pc, opcode| log.borrow_mut().push((pc, opcode)))));
+
+    // Each alias takes as long as its documented opcode, and leaves the PC where it would
+    let mut steps = Vec::new();
+    while cpu.pc != 0x19 {
+      let cycles = cpu.execute_next_instruction();
+      steps.push((cpu.pc, cycles));
+    }
+    assert_eq!(steps, vec![
+      (0x01, 4), (0x02, 4), (0x03, 4), (0x04, 4), (0x05, 4), (0x06, 4), (0x07, 4),
+      (0x10, 10),
+      (0x20, 17), (0x13, 10),
+      (0x20, 17), (0x16, 10),
+      (0x20, 17), (0x19, 10),
+    ]);
+    assert_eq!(cpu.sp, 0x3000);
+    assert_eq!(cpu.bus.memory[0x2FFE..0x3000], [0x19, 0x00]);
+
+    // The callback gets the address of each undocumented opcode
+    assert_eq!(*reported.borrow(), vec![
+      (0x00, 0x08), (0x01, 0x10), (0x02, 0x18), (0x03, 0x20), (0x04, 0x28), (0x05, 0x30), (0x06, 0x38),
+      (0x07, 0xCB),
+      (0x10, 0xDD), (0x20, 0xD9),
+      (0x13, 0xED), (0x20, 0xD9),
+      (0x16, 0xFD), (0x20, 0xD9),
+    ]);
+
+    // Documented opcodes are never reported, and turning strict mode off stops the reports
+    cpu.execute_next_instruction();
+    assert_eq!(reported.borrow().len(), 14);
+    cpu.set_strict_mode(None);
+    cpu.pc = 0;
+    cpu.execute_next_instruction();
+    assert_eq!(reported.borrow().len(), 14);
+  }
+}
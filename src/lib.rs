@@ -0,0 +1,13 @@
+pub mod audio;
+pub mod bus;
+pub mod cpm;
+pub mod debugger;
+pub mod discrete_sound;
+pub mod gdb;
+pub mod intel8080;
+pub mod machine;
+pub mod monitor;
+pub mod movie;
+pub mod recorder;
+pub mod rewind;
+pub mod video;
@@ -1,21 +1,43 @@
-use std::{io::Cursor, io::Read, time::Duration, time::Instant};
-use rodio::{OutputStream, OutputStreamHandle, Sink, Source};
+use std::{fmt, io::Error, io::ErrorKind, io::Read, io::Seek, path::Path, path::PathBuf, str::FromStr, time::Instant, time::SystemTime};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
 
-use crate::intel8080::Intel8080;
+use crate::audio::{AudioOutput, Mixer, NullAudio, SoundBoard, SAMPLES_PER_FRAME};
+use crate::discrete_sound::DiscreteSoundBoard;
+use crate::bus::{Bus, IoHandler};
+use crate::debugger::{BreakReason, Breakpoint, WatchHit, Watchpoint};
+use crate::intel8080::{Intel8080, Registers};
+use crate::movie::{InputEvent, Movie};
+use crate::rewind::RewindBuffer;
+use crate::video::{self, Overlay, ScreenshotOptions};
 
-const CYCLE_TIME: Duration = Duration::from_nanos(480);
-const INTERRUPT_INTERVAL: Duration = Duration::from_micros(8000);
+const CPU_CLOCK: u32 = 2_000_000;
+pub const FRAMES_PER_SECOND: u32 = 60;
+pub const CYCLES_PER_FRAME: u32 = CPU_CLOCK / FRAMES_PER_SECOND;
+// The video hardware counts 262 lines per frame, of which the first 224 are visible
+const SCANLINES_PER_FRAME: u32 = 262;
+// RST 1 is requested when the beam reaches the middle of the screen, and RST 2 when it reaches the end
+const INTERRUPTS: [(u32, u8); 2] = [
+  (CYCLES_PER_FRAME * 96 / SCANLINES_PER_FRAME, 1),
+  (CYCLES_PER_FRAME * 224 / SCANLINES_PER_FRAME, 2),
+];
 
-const SHOOT: &[u8] = include_bytes!("../sounds/shoot.flac");
-const BEAT1: &[u8] = include_bytes!("../sounds/fastinvader1.flac");
-const BEAT2: &[u8] = include_bytes!("../sounds/fastinvader2.flac");
-const BEAT3: &[u8] = include_bytes!("../sounds/fastinvader3.flac");
-const BEAT4: &[u8] = include_bytes!("../sounds/fastinvader4.flac");
-const EXPLOSION: &[u8] = include_bytes!("../sounds/explosion.flac");
-const INVADER_KILLED: &[u8] = include_bytes!("../sounds/invaderkilled.flac");
-const UFO_HIGH_PITCH: &[u8] = include_bytes!("../sounds/ufo_highpitch.flac");
-const UFO_LOW_PITCH: &[u8] = include_bytes!("../sounds/ufo_lowpitch.flac");
+// Save states start with this, followed by the format version as a little-endian u32 and then the state as a flexbuffer
+const SAVE_STATE_MAGIC: &[u8; 4] = b"SI80";
+const SAVE_STATE_VERSION: u32 = 2;
 
+// A snapshot is kept for rewinding every this many frames, so rewinding runs this many times faster than the game
+const REWIND_INTERVAL: u64 = 4;
+
+// The speeds the front-end can step through, as multiples of the real hardware's frame rate
+pub const SPEED_MULTIPLIERS: [f32; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+const NORMAL_SPEED: usize = 2;
+
+// The bits of input port 2 that come from the DIP switches rather than the controls:
+// the number of lives, the score for a bonus life, and whether the coin information is shown
+const DIP_SWITCHES: u8 = 0b1000_1011;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PlayerKey {
   Coin,
   Tilt,
@@ -29,58 +51,284 @@ pub enum PlayerKey {
   P2Start,
 }
 
+impl FromStr for PlayerKey {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    match name.to_ascii_lowercase().as_str() {
+      "coin" => Ok(PlayerKey::Coin),
+      "tilt" => Ok(PlayerKey::Tilt),
+      "p1left" => Ok(PlayerKey::P1Left),
+      "p1right" => Ok(PlayerKey::P1Right),
+      "p1fire" => Ok(PlayerKey::P1Fire),
+      "p1start" => Ok(PlayerKey::P1Start),
+      "p2left" => Ok(PlayerKey::P2Left),
+      "p2right" => Ok(PlayerKey::P2Right),
+      "p2fire" => Ok(PlayerKey::P2Fire),
+      "p2start" => Ok(PlayerKey::P2Start),
+      _ => Err(format!("Unknown key {}", name)),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+  Multiplier(f32),
+  // As many frames as the host has time for
+  Unthrottled,
+}
+
+impl fmt::Display for Speed {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Speed::Multiplier(multiplier) => write!(formatter, "{}x", multiplier),
+      Speed::Unthrottled => write!(formatter, "Fast-forward"),
+    }
+  }
+}
+
+// The hardware shift register, which lets the game draw sprites at any horizontal position without shifting them itself
+#[derive(Default, Serialize, Deserialize)]
+pub struct ShiftRegister {
+  shift0: u8,
+  shift1: u8,
+  shift_offset: u8,
+}
+
+impl IoHandler for ShiftRegister {
+  fn input(&mut self, port: u8) -> u8 {
+    match port {
+      3 => {
+        let v = (self.shift1 as u16) << 8 | self.shift0 as u16;
+        ((v >> (8 - self.shift_offset)) & 0x00FF) as u8
+      }
+      _ => 0,
+    }
+  }
+
+  fn output(&mut self, port: u8, value: u8) {
+    match port {
+      2 => self.shift_offset = value & 0x07,
+      4 => {
+        self.shift0 = self.shift1;
+        self.shift1 = value;
+      }
+      _ => (),
+    }
+  }
+}
+
+// The Space Invaders memory map: 8K of ROM followed by 8K of RAM, which is mirrored above 0x4000
+#[derive(Serialize, Deserialize)]
+pub struct SpaceInvadersBus {
+  // The ROM is loaded separately, so it is not part of a save state
+  #[serde(skip)]
+  rom: Vec<u8>,
+  #[serde(with = "serde_bytes")]
+  ram: Vec<u8>,
+  input_ports: [u8; 3],
+  shift_register: ShiftRegister,
+  out_port3: u8,
+  out_port5: u8,
+}
+
+impl Default for SpaceInvadersBus {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl SpaceInvadersBus {
+  pub fn new() -> Self {
+    SpaceInvadersBus {
+      rom: vec![0; 0x2000],
+      ram: vec![0; 0x2000],
+      input_ports: [
+        0b1110,
+        0b1000,
+        0b1011, // "Easy mode" -- start with 6 lives, gain a new life every 1000 points
+      ],
+      shift_register: ShiftRegister::default(),
+      out_port3: 0,
+      out_port5: 0,
+    }
+  }
+}
+
+impl Bus for SpaceInvadersBus {
+  fn peek(&self, address: u16) -> u8 {
+    match address & 0x3FFF {
+      address @ 0x0000..=0x1FFF => self.rom[address as usize],
+      address => self.ram[address as usize - 0x2000],
+    }
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    match address & 0x3FFF {
+      // The ROM can't be written to, and the board ignores any writes to it
+      0x0000..=0x1FFF => (),
+      mirrored => self.ram[mirrored as usize - 0x2000] = value,
+    }
+  }
+}
+
+impl IoHandler for SpaceInvadersBus {
+  fn input(&mut self, port: u8) -> u8 {
+    match port {
+      0..=2 => self.input_ports[port as usize],
+      3 => self.shift_register.input(port),
+      _ => 0,
+    }
+  }
+
+  fn output(&mut self, port: u8, value: u8) {
+    match port {
+      2 | 4 => self.shift_register.output(port, value),
+      // The sound hardware is driven directly by the bits latched on ports 3 and 5
+      3 => self.out_port3 = value,
+      5 => self.out_port5 = value,
+      // Port 6 resets the watchdog timer, which is not emulated
+      _ => (),
+    }
+  }
+}
+
+// Everything needed to resume emulation, other than the ROM
+#[derive(Serialize, Deserialize)]
+struct SaveState<C> {
+  cpu: C,
+  frame: u64,
+  frame_cycles: u32,
+  next_interrupt: usize,
+  last_out_port3: u8,
+  last_out_port5: u8,
+}
+
+fn encode_save_state(state: &SaveState<&Intel8080<SpaceInvadersBus>>) -> Vec<u8> {
+  let mut data = SAVE_STATE_MAGIC.to_vec();
+  data.extend_from_slice(&SAVE_STATE_VERSION.to_le_bytes());
+  data.extend_from_slice(&flexbuffers::to_vec(state).expect("Machine state should always be serializable"));
+
+  data
+}
+
+fn decode_save_state(data: &[u8]) -> std::io::Result<SaveState<Intel8080<SpaceInvadersBus>>> {
+  if data.len() < 8 || &data[0..4] != SAVE_STATE_MAGIC {
+    return Err(Error::new(ErrorKind::InvalidData, "Not a save state"));
+  }
+  let version = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+  if version != SAVE_STATE_VERSION {
+    return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported save state version {}", version)));
+  }
+
+  let state: SaveState<Intel8080<SpaceInvadersBus>> =
+    flexbuffers::from_slice(&data[8..]).map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+  // Save states come from files, so anything the bus would index with has to be checked before it is used
+  let bus = state.cpu.bus();
+  if bus.ram.len() != 0x2000 {
+    return Err(Error::new(ErrorKind::InvalidData, format!("Save state has {} bytes of RAM", bus.ram.len())));
+  }
+  if bus.shift_register.shift_offset > 7 {
+    return Err(Error::new(ErrorKind::InvalidData, "Save state has an invalid shift register offset"));
+  }
+
+  Ok(state)
+}
+
+enum MovieState {
+  Idle,
+  Recording { movie: Movie, start_frame: u64 },
+  Playing { movie: Movie, start_frame: u64, next_event: usize },
+}
+
 pub struct Machine {
-  cpu: Intel8080,
+  cpu: Intel8080<SpaceInvadersBus>,
   rom_size: u16,
-  last_interrupt_time: Option<Instant>,
-  next_interrupt: u8,
-  last_execution_time: Option<Instant>,
-  out_port3: u8,
+  frame: u64,
+  // Cycles executed so far in the current frame
+  frame_cycles: u32,
+  // Index into INTERRUPTS of the next interrupt to request this frame
+  next_interrupt: usize,
   last_out_port3: u8,
-  out_port5: u8,
   last_out_port5: u8,
-  shift0: u8,
-  shift1: u8,
-  shift_offset: u8,
-  _stream: OutputStream,
-  stream_handle: OutputStreamHandle,
-  ufo_sink: Sink,
+  mixer: Mixer,
+  overlay: Overlay,
+  movie: MovieState,
+  rewind: Option<RewindBuffer>,
+  paused: bool,
+  // Index into SPEED_MULTIPLIERS
+  speed: usize,
+  // Overrides the speed for as long as it is set
+  fast_forward: bool,
+  // The fraction of a frame carried over between updates when running slower than normal
+  frame_credit: f32,
+  // Why the debugger last stopped the CPU, until the front-end has reported it
+  last_break: Option<BreakReason>,
+  // Why recording to a WAV file stopped, until the front-end has reported it
+  wav_error: Option<Error>,
+}
+
+impl Default for Machine {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Machine {
+  // A machine without sound, which needs no audio device
   pub fn new() -> Self {
-    let (stream, stream_handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&stream_handle).unwrap();
-    sink.pause();
-    let sound = rodio::Decoder::new(Cursor::new(UFO_HIGH_PITCH)).unwrap();
-    sink.append(sound.repeat_infinite());
+    Self::with_audio(Box::new(NullAudio))
+  }
 
+  pub fn with_audio(audio: Box<dyn AudioOutput>) -> Self {
     Machine {
-      cpu: {
-        let mut cpu = Intel8080::new();
-        cpu.input_ports[0] = 0b1110;
-        cpu.input_ports[1] = 0b1000;
-        cpu.input_ports[2] = 0b1011; // "Easy mode" -- start with 6 lives, gain a new life every 1000 points
-        cpu
-      },
+      cpu: Intel8080::new(SpaceInvadersBus::new()),
       rom_size: 0,
-      last_interrupt_time: None,
-      next_interrupt: 1,
-      last_execution_time: None,
-      out_port3: 0,
+      frame: 0,
+      frame_cycles: 0,
+      next_interrupt: 0,
       last_out_port3: 0,
-      out_port5: 0,
       last_out_port5: 0,
-      shift0: 0,
-      shift1: 0,
-      shift_offset: 0,
-      _stream: stream,
-      stream_handle,
-      ufo_sink: sink,
+      mixer: Mixer::new(Box::new(DiscreteSoundBoard::new()), audio),
+      overlay: Overlay::default(),
+      movie: MovieState::Idle,
+      rewind: None,
+      paused: false,
+      speed: NORMAL_SPEED,
+      fast_forward: false,
+      frame_credit: 0.0,
+      last_break: None,
+      wav_error: None,
     }
   }
 
-  #[allow(dead_code)]
+  pub fn set_audio(&mut self, audio: Box<dyn AudioOutput>) {
+    self.mixer.set_output(audio);
+  }
+
+  pub fn set_sound_board(&mut self, board: Box<dyn SoundBoard>) {
+    self.mixer.set_board(board);
+    self.mixer.restore(self.last_out_port3, self.last_out_port5);
+  }
+
+  // Everything the game plays from now on is also written to a WAV file, in step with emulated time
+  pub fn start_wav_recording(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+    self.mixer.start_wav(path)
+  }
+
+  pub fn stop_wav_recording(&mut self) -> std::io::Result<()> {
+    self.mixer.stop_wav()
+  }
+
+  pub fn is_recording_wav(&self) -> bool {
+    self.mixer.is_recording_wav()
+  }
+
+  // Why writing the WAV file failed, if it has since the last call, which also stops the recording
+  pub fn take_wav_error(&mut self) -> Option<Error> {
+    self.wav_error.take()
+  }
+
   pub fn load_rom_bytes(&mut self, bytes: &[u8]) {
     self.load_rom_bytes_at(bytes, 0);
   }
@@ -88,10 +336,9 @@ impl Machine {
   pub fn load_rom_bytes_at(&mut self, bytes: &[u8], offset: u16) {
     //TODO: This is not correct if the ROM is loaded in parts
     self.rom_size = bytes.len() as u16;
-    self.cpu.memory[offset as usize..offset as usize + self.rom_size as usize].copy_from_slice(bytes);
+    self.cpu.bus_mut().rom[offset as usize..offset as usize + self.rom_size as usize].copy_from_slice(bytes);
   }
 
-  #[allow(dead_code)]
   pub fn load_rom(&mut self, reader: &mut impl Read) -> std::io::Result<()> {
     self.load_rom_at(reader, 0)?;
 
@@ -100,156 +347,816 @@ impl Machine {
 
   pub fn load_rom_at(&mut self, reader: &mut impl Read, offset: u16) -> std::io::Result<()> {
     //TODO: This is not correct if the ROM is loaded in parts
-    self.rom_size = reader.read(&mut self.cpu.memory[offset as usize..])? as u16;
+    self.rom_size = reader.read(&mut self.cpu.bus_mut().rom[offset as usize..])? as u16;
+
+    Ok(())
+  }
+
+  // Loads the four parts of the Space Invaders ROM set from the MAME zip
+  pub fn load_rom_zip(&mut self, reader: impl Read + Seek) -> zip::result::ZipResult<()> {
+    let mut zip = zip::ZipArchive::new(reader)?;
+    self.load_rom(&mut zip.by_name("invaders.h")?)?;
+    self.load_rom_at(&mut zip.by_name("invaders.g")?, 0x800)?;
+    self.load_rom_at(&mut zip.by_name("invaders.f")?, 0x1000)?;
+    self.load_rom_at(&mut zip.by_name("invaders.e")?, 0x1800)?;
 
     Ok(())
   }
 
-  #[allow(dead_code)]
-  pub fn disassemble_rom(&self) {
-    let mut pc = 0;
-    while pc < self.rom_size {
-      pc += self.cpu.disassemble_8080_op(pc);
+  pub fn save_state(&self) -> Vec<u8> {
+    encode_save_state(&SaveState {
+      cpu: &self.cpu,
+      frame: self.frame,
+      frame_cycles: self.frame_cycles,
+      next_interrupt: self.next_interrupt,
+      last_out_port3: self.last_out_port3,
+      last_out_port5: self.last_out_port5,
+    })
+  }
+
+  pub fn load_state(&mut self, data: &[u8]) -> std::io::Result<()> {
+    match self.movie {
+      // A movie cannot represent jumping to another state
+      MovieState::Recording { .. } => Err(Error::new(ErrorKind::InvalidInput, "Cannot load a state while recording a movie")),
+      _ => {
+        self.restore_state(data)?;
+        self.movie = MovieState::Idle;
+        Ok(())
+      }
     }
   }
 
-  pub fn frame_buffer(&self) -> &[u8] {
-    // This is specific to Space Invaders
-    &self.cpu.memory[0x2400..0x4000]
+  fn restore_state(&mut self, data: &[u8]) -> std::io::Result<()> {
+    let state = decode_save_state(data)?;
+    let mut cpu = state.cpu;
+    std::mem::swap(&mut cpu.bus_mut().rom, &mut self.cpu.bus_mut().rom);
+    cpu.swap_debugger(&mut self.cpu);
+    self.cpu = cpu;
+    self.frame = state.frame;
+    self.frame_cycles = state.frame_cycles;
+    self.next_interrupt = state.next_interrupt;
+    self.last_out_port3 = state.last_out_port3;
+    self.last_out_port5 = state.last_out_port5;
+    self.mixer.restore(self.last_out_port3, self.last_out_port5);
+
+    Ok(())
   }
 
-  fn space_invaders_out(&mut self, port: u8, value: u8) {
-    match port {
-      2 => {
-        self.shift_offset = value & 0x07;
-        self.update_shift_register();
+  // Identifies the ROM set and DIP switch settings, which have to match for a movie to play back the same way
+  pub fn configuration_hash(&self) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&self.cpu.bus().rom);
+    hasher.update(&[self.cpu.bus().input_ports[2] & DIP_SWITCHES]);
+
+    hasher.finalize()
+  }
+
+  // Records every key press and release from now on, along with the current state to start from
+  pub fn start_movie_recording(&mut self) {
+    self.movie = MovieState::Recording {
+      movie: Movie {
+        configuration_hash: self.configuration_hash(),
+        start_state: self.save_state(),
+        events: Vec::new(),
+        length: 0,
+      },
+      start_frame: self.frame,
+    };
+  }
+
+  pub fn stop_movie_recording(&mut self) -> Option<Movie> {
+    match std::mem::replace(&mut self.movie, MovieState::Idle) {
+      MovieState::Recording { mut movie, start_frame } => {
+        movie.length = self.frame - start_frame;
+        Some(movie)
       }
-      3 => self.out_port3 = value,
-      4 => {
-        self.shift0 = self.shift1;
-        self.shift1 = value;
-        self.update_shift_register();
+      other => {
+        self.movie = other;
+        None
       }
-      5 => self.out_port5 = value,
-      _ => (),
     }
   }
 
-  fn update_shift_register(&mut self) {
-    self.cpu.input_ports[3] = {
-      let v = (self.shift1 as u16) << 8 | self.shift0 as u16;
-      ((v >> (8 - self.shift_offset)) & 0x00FF) as u8
+  pub fn is_recording_movie(&self) -> bool {
+    matches!(self.movie, MovieState::Recording { .. })
+  }
+
+  // Returns to the state the movie started from and replays its inputs, ignoring the player's, until it ends
+  pub fn play_movie(&mut self, movie: Movie) -> std::io::Result<()> {
+    if movie.configuration_hash != self.configuration_hash() {
+      return Err(Error::new(ErrorKind::InvalidData, "The movie was recorded with a different ROM set or DIP switches"));
+    }
+    self.restore_state(&movie.start_state)?;
+    self.movie = MovieState::Playing {
+      movie,
+      start_frame: self.frame,
+      next_event: 0,
     };
+
+    Ok(())
   }
 
-  pub fn execute(&mut self) {
-    #[cfg(not(feature = "cputest"))]
-    match self.last_interrupt_time {
-      Some(time) if time.elapsed() > INTERRUPT_INTERVAL => {
-        self.cpu.generate_interrupt(self.next_interrupt);
-        self.next_interrupt = match self.next_interrupt {
-          1 => 2,
-          _ => 1,
-        };
-        self.last_interrupt_time = Some(Instant::now());
-      }
-      Some(_) => (),
-      None => self.last_interrupt_time = Some(Instant::now()),
+  pub fn stop_movie_playback(&mut self) {
+    if self.is_playing_movie() {
+      self.movie = MovieState::Idle;
     }
+  }
 
-    if let Some(time) = self.last_execution_time {
-      let cycles_needed = (time.elapsed().as_secs_f64() / CYCLE_TIME.as_secs_f64()).ceil() as u32;
-      let mut cycles = 0;
-      while cycles < cycles_needed {
-        cycles += self.cpu.execute_next_instruction() as u32;
+  pub fn is_playing_movie(&self) -> bool {
+    matches!(self.movie, MovieState::Playing { .. })
+  }
 
-        if let Some((out_port, value)) = self.cpu.get_output() {
-          self.space_invaders_out(out_port, value);
-          self.play_sounds();
-        }
+  // Keeps enough snapshots to rewind the given number of seconds, or none at all for 0
+  pub fn set_rewind_seconds(&mut self, seconds: u32) {
+    self.rewind = match seconds {
+      0 => None,
+      _ => Some(RewindBuffer::new((seconds as u64 * FRAMES_PER_SECOND as u64 / REWIND_INTERVAL) as usize)),
+    };
+  }
 
-        self.last_execution_time = Some(Instant::now());
-      }
-    } else {
-      self.last_execution_time = Some(Instant::now());
+  // Steps back to the previous snapshot, returning false if there is nothing left to rewind to
+  pub fn rewind(&mut self) -> bool {
+    if self.is_recording_movie() {
+      return false;
     }
+    // The newest snapshot can be of the frame the machine is on now, and restoring it wouldn't go back at all
+    let current = self.save_state();
+    let state = match self.rewind.as_mut() {
+      Some(rewind) => match rewind.pop() {
+        Some(state) if state == current => rewind.pop(),
+        state => state,
+      },
+      None => None,
+    };
+    let state = match state {
+      Some(state) => state,
+      None => return false,
+    };
+    self.movie = MovieState::Idle;
+    self.restore_state(&state).expect("Rewind snapshots should always be valid save states");
+
+    true
   }
 
-  fn play_sounds(&mut self) {
-    if self.out_port3 != self.last_out_port3 {
-      if self.out_port3 & 0x1 == 0x1 && !(self.last_out_port3 & 0x1 == 0x1) {
-        self.ufo_sink.play();
-      }
-      if self.out_port3 & 0x1 == 0x0 && !(self.last_out_port3 & 0x1 == 0x0) {
-        self.ufo_sink.pause();
-      }
-      if self.out_port3 & 0x2 == 0x2 && !(self.last_out_port3 & 0x2 == 0x2) {
-        //TODO: In the actual arcade, shoot is a continuous sound that lasts until the laser hits something
-        let sound = rodio::Decoder::new(Cursor::new(SHOOT)).unwrap();
-        self.stream_handle.play_raw(sound.convert_samples()).unwrap();
-      }
-      if self.out_port3 & 0x4 == 0x4 && !(self.last_out_port3 & 0x4 == 0x4) {
-        let sound = rodio::Decoder::new(Cursor::new(EXPLOSION)).unwrap();
-        self.stream_handle.play_raw(sound.convert_samples()).unwrap();
+  pub fn registers(&self) -> Registers {
+    self.cpu.registers()
+  }
+
+  pub fn set_registers(&mut self, registers: &Registers) {
+    self.cpu.set_registers(registers);
+  }
+
+  pub fn read_memory(&self, address: u16) -> u8 {
+    self.cpu.bus().peek(address)
+  }
+
+  // Goes through the bus like a write from the CPU, so the ROM can't be changed
+  pub fn write_memory(&mut self, address: u16, value: u8) {
+    self.cpu.bus_mut().write(address, value);
+  }
+
+  pub fn ram(&self) -> &[u8] {
+    &self.cpu.bus().ram
+  }
+
+  pub fn frame_buffer(&self) -> &[u8] {
+    // This is specific to Space Invaders
+    &self.cpu.bus().ram[0x0400..0x2000]
+  }
+
+  pub fn overlay(&self) -> &Overlay {
+    &self.overlay
+  }
+
+  pub fn set_overlay(&mut self, overlay: Overlay) {
+    self.overlay = overlay;
+  }
+
+  // Draws the screen upright and colored by the overlay into a video::WIDTH x video::HEIGHT RGBA buffer
+  pub fn render_rgba(&self, buffer: &mut [u8]) {
+    video::render_rgba(self.frame_buffer(), &self.overlay, buffer);
+  }
+
+  pub fn screenshot(&self, options: &ScreenshotOptions) -> RgbaImage {
+    let mut screen = RgbaImage::new(video::WIDTH as u32, video::HEIGHT as u32);
+    match options.overlay {
+      true => self.render_rgba(&mut screen),
+      false => video::render_rgba(self.frame_buffer(), &Overlay::monochrome(), &mut screen),
+    }
+
+    video::compose_screenshot(&screen, options)
+  }
+
+  // Writes a screenshot to a PNG in the directory, named after the current time, and returns its path
+  pub fn save_screenshot(&self, directory: impl AsRef<Path>, options: &ScreenshotOptions) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(&directory)?;
+    let path = directory.as_ref().join(video::timestamped_file_name(SystemTime::now(), "png"));
+    self.screenshot(options).save(&path).map_err(Error::other)?;
+
+    Ok(path)
+  }
+
+  // Runs exactly one video frame's worth of CPU cycles, requesting each interrupt at its point in the frame
+  // If the debugger stops the CPU partway through, the machine is paused and the rest of the frame runs next time
+  pub fn run_frame(&mut self) -> Option<BreakReason> {
+    self.play_movie_inputs();
+
+    while self.frame_cycles < CYCLES_PER_FRAME {
+      if let Some(&(cycle, number)) = INTERRUPTS.get(self.next_interrupt) {
+        // The request stays pending until the CPU accepts it
+        if self.frame_cycles >= cycle {
+          match self.cpu.generate_interrupt(number) {
+            0 => (),
+            cycles => {
+              self.frame_cycles += cycles as u32;
+              self.next_interrupt += 1;
+            }
+          }
+        }
       }
-      if self.out_port3 & 0x8 == 0x8 && !(self.last_out_port3 & 0x8 == 0x8) {
-        let sound = rodio::Decoder::new(Cursor::new(INVADER_KILLED)).unwrap();
-        self.stream_handle.play_raw(sound.convert_samples()).unwrap();
+
+      if let Some(reason) = self.cpu.check_break() {
+        self.paused = true;
+        self.last_break = Some(reason);
+        return Some(reason);
       }
+
+      // A halted CPU does nothing until the next interrupt, but time still passes
+      self.frame_cycles += match self.cpu.execute_next_instruction() {
+        0 => 4,
+        cycles => cycles as u32,
+      };
+      self.play_sounds();
     }
-    if self.out_port5 != self.last_out_port5 {
-      if self.out_port5 & 0x1 == 0x1 && !(self.last_out_port5 & 0x1 == 0x1) {
-        let sound = rodio::Decoder::new(Cursor::new(BEAT1)).unwrap();
-        self.stream_handle.play_raw(sound.convert_samples()).unwrap();
+
+    self.end_frame();
+
+    None
+  }
+
+  fn end_frame(&mut self) {
+    // Any cycles that ran over the end of the frame count towards the next one
+    self.frame_cycles -= CYCLES_PER_FRAME;
+    self.next_interrupt = 0;
+    self.frame += 1;
+    if let MovieState::Playing { movie, start_frame, .. } = &self.movie {
+      if self.frame - start_frame >= movie.length {
+        self.movie = MovieState::Idle;
       }
-      if self.out_port5 & 0x2 == 0x2 && !(self.last_out_port5 & 0x2 == 0x2) {
-        let sound = rodio::Decoder::new(Cursor::new(BEAT2)).unwrap();
-        self.stream_handle.play_raw(sound.convert_samples()).unwrap();
+    }
+    if let Some(mut rewind) = self.rewind.take() {
+      if self.frame.is_multiple_of(REWIND_INTERVAL) {
+        rewind.push(self.save_state());
       }
-      if self.out_port5 & 0x4 == 0x4 && !(self.last_out_port5 & 0x4 == 0x4) {
-        let sound = rodio::Decoder::new(Cursor::new(BEAT3)).unwrap();
-        self.stream_handle.play_raw(sound.convert_samples()).unwrap();
+      self.rewind = Some(rewind);
+    }
+    if let Err(error) = self.mixer.end_frame() {
+      self.wav_error = Some(error);
+    }
+  }
+
+  fn play_movie_inputs(&mut self) {
+    if let MovieState::Playing { movie, start_frame, next_event } = &mut self.movie {
+      let frame = self.frame - *start_frame;
+      while let Some(event) = movie.events.get(*next_event).filter(|event| event.frame <= frame) {
+        set_key(&mut self.cpu.bus_mut().input_ports, event.key, event.pressed);
+        *next_event += 1;
       }
-      if self.out_port5 & 0x8 == 0x8 && !(self.last_out_port5 & 0x8 == 0x8) {
-        let sound = rodio::Decoder::new(Cursor::new(BEAT4)).unwrap();
-        self.stream_handle.play_raw(sound.convert_samples()).unwrap();
+    }
+  }
+
+  pub fn frame_count(&self) -> u64 {
+    self.frame
+  }
+
+  pub fn pause(&mut self) {
+    self.paused = true;
+  }
+
+  pub fn resume(&mut self) {
+    self.cpu.resume();
+    self.paused = false;
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused
+  }
+
+  // Runs a single frame, or the rest of one the debugger stopped in, and leaves the machine paused
+  pub fn advance_frame(&mut self) -> Option<BreakReason> {
+    self.cpu.resume();
+    self.paused = true;
+    self.run_frame()
+  }
+
+  pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+    self.cpu.add_breakpoint(breakpoint);
+  }
+
+  pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+    self.cpu.remove_breakpoint(address)
+  }
+
+  pub fn breakpoints(&self) -> &[Breakpoint] {
+    self.cpu.breakpoints()
+  }
+
+  pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+    self.cpu.add_watchpoint(watchpoint);
+  }
+
+  pub fn remove_watchpoint(&mut self, address: u16) -> bool {
+    self.cpu.remove_watchpoint(address)
+  }
+
+  pub fn watchpoints(&self) -> &[Watchpoint] {
+    self.cpu.watchpoints()
+  }
+
+  pub fn take_watch_log(&mut self) -> Vec<WatchHit> {
+    self.cpu.take_watch_log()
+  }
+
+  // The stepping controls set where the CPU should stop and let the machine run until it gets there, which may be
+  // partway through a frame, so interrupts and sound carry on as normal
+  pub fn step_into(&mut self) {
+    self.cpu.step_into();
+    self.paused = false;
+  }
+
+  pub fn step_over(&mut self) {
+    self.cpu.step_over();
+    self.paused = false;
+  }
+
+  pub fn step_out(&mut self) {
+    self.cpu.step_out();
+    self.paused = false;
+  }
+
+  pub fn run_to(&mut self, address: u16) {
+    self.cpu.run_to(address);
+    self.paused = false;
+  }
+
+  // Why the debugger stopped the CPU, if it has since the last call
+  pub fn take_break(&mut self) -> Option<BreakReason> {
+    self.last_break.take()
+  }
+
+  pub fn disassemble(&self, address: u16) -> (String, u16) {
+    self.cpu.disassemble(address)
+  }
+
+  pub fn speed(&self) -> Speed {
+    match self.fast_forward {
+      true => Speed::Unthrottled,
+      false => Speed::Multiplier(SPEED_MULTIPLIERS[self.speed]),
+    }
+  }
+
+  pub fn faster(&mut self) {
+    self.speed = (self.speed + 1).min(SPEED_MULTIPLIERS.len() - 1);
+  }
+
+  pub fn slower(&mut self) {
+    self.speed = self.speed.saturating_sub(1);
+  }
+
+  pub fn reset_speed(&mut self) {
+    self.speed = NORMAL_SPEED;
+  }
+
+  pub fn set_fast_forward(&mut self, fast_forward: bool) {
+    self.fast_forward = fast_forward;
+  }
+
+  // Call at the real hardware's frame rate: runs however many frames are due at the current speed, calling on_frame
+  // after each one, and returns how many ran
+  // A debugger stop pauses the machine partway through a frame, which doesn't count
+  // Nothing runs while paused, and no new frame is started once the deadline has passed so a slow host can catch up
+  pub fn update(&mut self, deadline: Instant, mut on_frame: impl FnMut(&mut Self)) -> u32 {
+    if self.paused {
+      return 0;
+    }
+    let due = match self.speed() {
+      Speed::Multiplier(multiplier) => {
+        self.frame_credit += multiplier;
+        let due = self.frame_credit as u32;
+        self.frame_credit -= due as f32;
+        due
       }
-      if self.out_port5 & 0x10 == 0x10 && !(self.last_out_port5 & 0x10 == 0x10) {
-        let sound = rodio::Decoder::new(Cursor::new(UFO_LOW_PITCH)).unwrap();
-        self.stream_handle.play_raw(sound.convert_samples()).unwrap();
+      Speed::Unthrottled => u32::MAX,
+    };
+
+    let mut frames = 0;
+    while frames < due && (frames == 0 || Instant::now() < deadline) {
+      if self.run_frame().is_some() {
+        break;
       }
+      on_frame(self);
+      frames += 1;
     }
-    self.last_out_port3 = self.out_port3;
-    self.last_out_port5 = self.out_port5;
+
+    frames
   }
 
-  pub fn key_down(&mut self, key: PlayerKey) {
-    match key {
-        PlayerKey::Coin => self.cpu.input_ports[1] |= 0x01,
-        PlayerKey::Tilt => self.cpu.input_ports[2] |= 0x04,
-        PlayerKey::P1Left => self.cpu.input_ports[1] |= 0x20,
-        PlayerKey::P1Right => self.cpu.input_ports[1] |= 0x40,
-        PlayerKey::P1Fire => self.cpu.input_ports[1] |= 0x10,
-        PlayerKey::P1Start => self.cpu.input_ports[1] |= 0x04,
-        PlayerKey::P2Left => self.cpu.input_ports[2] |= 0x20,
-        PlayerKey::P2Right => self.cpu.input_ports[2] |= 0x40,
-        PlayerKey::P2Fire => self.cpu.input_ports[2] |= 0x10,
-        PlayerKey::P2Start => self.cpu.input_ports[1] |= 0x02,
+  fn play_sounds(&mut self) {
+    let out_port3 = self.cpu.bus().out_port3;
+    let out_port5 = self.cpu.bus().out_port5;
+    if out_port3 != self.last_out_port3 || out_port5 != self.last_out_port5 {
+      let sample = self.frame_cycles as usize * SAMPLES_PER_FRAME / CYCLES_PER_FRAME as usize;
+      self.mixer.set_ports(sample, out_port3, out_port5);
+      self.last_out_port3 = out_port3;
+      self.last_out_port5 = out_port5;
     }
   }
 
+  pub fn key_down(&mut self, key: PlayerKey) {
+    self.player_input(key, true);
+  }
+
   pub fn key_up(&mut self, key: PlayerKey) {
-    match key {
-        PlayerKey::Coin => self.cpu.input_ports[1] &= !0x01,
-        PlayerKey::Tilt => self.cpu.input_ports[2] &= !0x04,
-        PlayerKey::P1Left => self.cpu.input_ports[1] &= !0x20,
-        PlayerKey::P1Right => self.cpu.input_ports[1] &= !0x40,
-        PlayerKey::P1Fire => self.cpu.input_ports[1] &= !0x10,
-        PlayerKey::P1Start => self.cpu.input_ports[1] &= !0x04,
-        PlayerKey::P2Left => self.cpu.input_ports[2] &= !0x20,
-        PlayerKey::P2Right => self.cpu.input_ports[2] &= !0x40,
-        PlayerKey::P2Fire => self.cpu.input_ports[2] &= !0x10,
-        PlayerKey::P2Start => self.cpu.input_ports[1] &= !0x02,
+    self.player_input(key, false);
+  }
+
+  fn player_input(&mut self, key: PlayerKey, pressed: bool) {
+    match &mut self.movie {
+      // The movie is in control
+      MovieState::Playing { .. } => return,
+      MovieState::Recording { movie, start_frame } => movie.events.push(InputEvent {
+        frame: self.frame - *start_frame,
+        key,
+        pressed,
+      }),
+      MovieState::Idle => (),
+    }
+    set_key(&mut self.cpu.bus_mut().input_ports, key, pressed);
+  }
+}
+
+fn set_key(input_ports: &mut [u8; 3], key: PlayerKey, pressed: bool) {
+  let (port, bit) = match key {
+    PlayerKey::Coin => (1, 0x01),
+    PlayerKey::Tilt => (2, 0x04),
+    PlayerKey::P1Left => (1, 0x20),
+    PlayerKey::P1Right => (1, 0x40),
+    PlayerKey::P1Fire => (1, 0x10),
+    PlayerKey::P1Start => (1, 0x04),
+    PlayerKey::P2Left => (2, 0x20),
+    PlayerKey::P2Right => (2, 0x40),
+    PlayerKey::P2Fire => (2, 0x10),
+    PlayerKey::P2Start => (1, 0x02),
+  };
+  match pressed {
+    true => input_ports[port] |= bit,
+    false => input_ports[port] &= !bit,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{cell::RefCell, rc::Rc};
+
+  // Counts each interrupt in RAM, and turns on the UFO sound in the end-of-screen interrupt
+  const INTERRUPT_COUNTER: [u8; 0x18] = [
+    0x31, 0x00, 0x24, // LXI SP, $2400
+    0xFB, // EI
+    0xC3, 0x03, 0x00, // JMP $0003
+    0x00, // NOP
+    0x21, 0x00, 0x20, // LXI H, $2000
+    0x34, // INR M
+    0xC9, // RET
+    0x00, 0x00, 0x00, // NOP
+    0x21, 0x01, 0x20, // LXI H, $2001
+    0x34, // INR M
+    0x3E, 0x01, // MVI A, 1
+    0xD3, 0x03, // OUT 3
+  ];
+
+  struct RecordingAudio {
+    frames: Rc<RefCell<Vec<Vec<f32>>>>,
+  }
+
+  impl AudioOutput for RecordingAudio {
+    fn play(&mut self, samples: &[f32]) {
+      self.frames.borrow_mut().push(samples.to_vec());
+    }
+  }
+
+  fn interrupt_counter() -> Machine {
+    let mut machine = Machine::new();
+    machine.load_rom_bytes(&INTERRUPT_COUNTER);
+    machine.cpu.bus_mut().rom[INTERRUPT_COUNTER.len()] = 0xC9; // RET
+    machine
+  }
+
+  #[test]
+  fn space_invaders_memory_map() {
+    let mut bus = SpaceInvadersBus::new();
+    bus.rom[0x0123] = 0xAA;
+
+    // ROM cannot be written
+    bus.write(0x0123, 0x55);
+    assert_eq!(bus.read(0x0123), 0xAA);
+
+    // RAM is mirrored above 0x4000
+    bus.write(0x2400, 0x42);
+    assert_eq!(bus.read(0x6400), 0x42);
+    bus.write(0xE400, 0x24);
+    assert_eq!(bus.read(0x2400), 0x24);
+    assert_eq!(bus.read(0x4123), 0xAA);
+  }
+
+  #[test]
+  fn instructions_cannot_write_rom() {
+    let mut bus = SpaceInvadersBus::new();
+    let program = [
+      0x01, 0x00, 0x01, // LXI B, $0100
+      0x02, // STAX B
+      0x21, 0x01, 0x01, // LXI H, $0101
+      0x34, // INR M
+      0x22, 0x02, 0x01, // SHLD $0102
+      0x31, 0x04, 0x01, // LXI SP, $0104
+      0xE3, // XTHL
+    ];
+    bus.rom[..program.len()].copy_from_slice(&program);
+    let mut cpu = Intel8080::new(bus);
+    for _ in 0..7 {
+      cpu.execute_next_instruction();
+    }
+    assert_eq!(cpu.bus().rom[0x0100..0x0106], [0; 6]);
+  }
+
+  #[test]
+  fn save_state_round_trip() {
+    let mut cpu = Intel8080::new(SpaceInvadersBus::new());
+    cpu.bus_mut().rom[0] = 0xC3;
+    cpu.bus_mut().ram[0x0400] = 0x5A;
+    cpu.bus_mut().input_ports[2] = 0b1000_0011;
+    cpu.bus_mut().output(4, 0x12);
+    cpu.bus_mut().output(4, 0x34);
+    cpu.bus_mut().output(2, 3);
+    cpu.bus_mut().output(3, 0x01);
+    cpu.set_hl(0x2400);
+    cpu.set_sp(0x23FE);
+    cpu.set_pc(0x1234);
+    cpu.set_interrupts_enabled(true);
+
+    let data = encode_save_state(&SaveState {
+      cpu: &cpu,
+      frame: 1234,
+      frame_cycles: 100,
+      next_interrupt: 1,
+      last_out_port3: 0x01,
+      last_out_port5: 0x10,
+    });
+    let mut state = decode_save_state(&data).unwrap();
+    assert_eq!(state.cpu.registers(), cpu.registers());
+    assert_eq!(state.cpu.bus().ram, cpu.bus().ram);
+    assert_eq!(state.cpu.bus().input_ports, [0b1110, 0b1000, 0b1000_0011]);
+    assert_eq!(state.cpu.bus().out_port3, 0x01);
+    // The ROM is not saved
+    assert!(state.cpu.bus().rom.is_empty());
+    assert_eq!(state.cpu.bus_mut().input(3), 0xA0);
+    assert_eq!((state.frame, state.frame_cycles, state.next_interrupt), (1234, 100, 1));
+    assert_eq!((state.last_out_port3, state.last_out_port5), (0x01, 0x10));
+
+    // Anything else is rejected
+    assert!(decode_save_state(b"SI80").is_err());
+    let mut future = data.clone();
+    future[4] = 0xFF;
+    assert!(decode_save_state(&future).is_err());
+
+    // So are states that would make the bus index out of bounds
+    cpu.bus_mut().ram.truncate(0x0400);
+    let short_ram = encode_save_state(&SaveState {
+      cpu: &cpu,
+      frame: 1234,
+      frame_cycles: 100,
+      next_interrupt: 1,
+      last_out_port3: 0x01,
+      last_out_port5: 0x10,
+    });
+    assert_eq!(decode_save_state(&short_ram).err().map(|error| error.kind()), Some(ErrorKind::InvalidData));
+    cpu.bus_mut().ram.resize(0x2000, 0);
+    cpu.bus_mut().shift_register.shift_offset = 8;
+    let bad_offset = encode_save_state(&SaveState {
+      cpu: &cpu,
+      frame: 1234,
+      frame_cycles: 100,
+      next_interrupt: 1,
+      last_out_port3: 0x01,
+      last_out_port5: 0x10,
+    });
+    assert_eq!(decode_save_state(&bad_offset).err().map(|error| error.kind()), Some(ErrorKind::InvalidData));
+  }
+
+  #[test]
+  fn shift_register() {
+    let mut shift_register = ShiftRegister::default();
+    shift_register.output(4, 0xAB);
+    shift_register.output(4, 0xCD);
+    shift_register.output(2, 0);
+    assert_eq!(shift_register.input(3), 0xCD);
+    shift_register.output(2, 4);
+    assert_eq!(shift_register.input(3), 0xDA);
+    shift_register.output(2, 7);
+    assert_eq!(shift_register.input(3), 0xD5);
+
+    // The shift register is wired to ports 2, 3 and 4 of the bus
+    let mut bus = SpaceInvadersBus::new();
+    bus.output(4, 0x0F);
+    bus.output(2, 2);
+    assert_eq!(bus.input(3), 0x3C);
+  }
+
+  #[test]
+  fn run_frame_requests_both_interrupts() {
+    let mut machine = interrupt_counter();
+    machine.run_frame();
+    assert_eq!(machine.frame_count(), 1);
+    assert_eq!(machine.cpu.bus().ram[0..2], [1, 1]);
+
+    for _ in 0..9 {
+      machine.run_frame();
+    }
+    assert_eq!(machine.frame_count(), 10);
+    assert_eq!(machine.cpu.bus().ram[0..2], [10, 10]);
+    assert!(machine.frame_cycles < 20);
+  }
+
+  #[test]
+  fn run_frame_is_deterministic() {
+    let mut first = interrupt_counter();
+    let mut second = interrupt_counter();
+    for _ in 0..5 {
+      first.run_frame();
+      second.run_frame();
+    }
+    assert_eq!(first.save_state(), second.save_state());
+
+    // Restoring a state resumes at exactly the same point in the frame
+    second.run_frame();
+    first.load_state(&second.save_state()).unwrap();
+    first.run_frame();
+    second.run_frame();
+    assert_eq!(first.save_state(), second.save_state());
+  }
+
+  #[test]
+  fn audio_is_one_frame_of_samples_per_frame() {
+    let frames = Rc::new(RefCell::new(Vec::new()));
+    let mut machine = interrupt_counter();
+    machine.set_audio(Box::new(RecordingAudio { frames: frames.clone() }));
+    for _ in 0..3 {
+      machine.run_frame();
+    }
+    assert_eq!(frames.borrow().len(), 3);
+    assert!(frames.borrow().iter().all(|samples| samples.len() == SAMPLES_PER_FRAME));
+  }
+
+  #[test]
+  fn wav_recording() {
+    let path = std::env::temp_dir().join(format!("emulate-8080-test-{}.wav", std::process::id()));
+    let mut machine = interrupt_counter();
+    machine.run_frame();
+    machine.start_wav_recording(&path).unwrap();
+    assert!(machine.is_recording_wav());
+    machine.run_frame();
+    machine.run_frame();
+    machine.stop_wav_recording().unwrap();
+    assert!(!machine.is_recording_wav());
+    machine.run_frame();
+
+    let reader = hound::WavReader::open(&path).unwrap();
+    assert_eq!(reader.spec().sample_rate, crate::audio::SAMPLE_RATE);
+    assert_eq!(reader.len() as usize, 2 * SAMPLES_PER_FRAME);
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn movie_playback_is_exact() {
+    // Keeps adding the value of input port 1 to a total, so the result depends on exactly when each key changed
+    let program = [
+      0x31, 0x00, 0x24, // LXI SP, $2400
+      0xDB, 0x01, // IN 1
+      0x47, // MOV B, A
+      0x3A, 0x02, 0x20, // LDA $2002
+      0x80, // ADD B
+      0x32, 0x02, 0x20, // STA $2002
+      0xC3, 0x03, 0x00, // JMP $0003
+    ];
+    let new_machine = || {
+      let mut machine = Machine::new();
+      machine.load_rom_bytes(&program);
+      machine
+    };
+
+    let mut machine = new_machine();
+    for _ in 0..5 {
+      machine.run_frame();
+    }
+    machine.start_movie_recording();
+    assert!(machine.load_state(&machine.save_state()).is_err());
+    for frame in 0..30 {
+      match frame {
+        3 => machine.key_down(PlayerKey::Coin),
+        7 => machine.key_up(PlayerKey::Coin),
+        10 => machine.key_down(PlayerKey::P1Start),
+        20 => machine.key_up(PlayerKey::P1Start),
+        _ => (),
+      }
+      machine.run_frame();
+    }
+    let movie = machine.stop_movie_recording().unwrap();
+    assert!(!machine.is_recording_movie());
+    assert_eq!((movie.length, movie.events.len()), (30, 4));
+
+    let mut replay = new_machine();
+    replay.play_movie(movie.clone()).unwrap();
+    for _ in 0..30 {
+      assert!(replay.is_playing_movie());
+      // The player cannot interfere with the movie
+      replay.key_down(PlayerKey::P1Fire);
+      replay.run_frame();
+    }
+    assert!(!replay.is_playing_movie());
+    assert_eq!(replay.save_state(), machine.save_state());
+
+    // A different ROM set or DIP switch setting is detected
+    let mut other = new_machine();
+    other.key_down(PlayerKey::Coin);
+    assert!(other.play_movie(movie.clone()).is_ok());
+    other.cpu.bus_mut().input_ports[2] ^= 0x01;
+    assert!(other.play_movie(movie.clone()).is_err());
+    let mut other = new_machine();
+    other.cpu.bus_mut().rom[0x1000] = 0xFF;
+    assert!(other.play_movie(movie).is_err());
+  }
+
+  #[test]
+  fn rewind() {
+    let mut machine = interrupt_counter();
+    assert!(!machine.rewind());
+    machine.set_rewind_seconds(1);
+    for _ in 0..100 {
+      machine.run_frame();
+    }
+
+    // Snapshots are taken every few frames, and only a second's worth are kept. The one just taken of frame 100 is
+    // skipped, so every step goes back in time
+    assert!(machine.rewind());
+    assert_eq!(machine.frame_count(), 96);
+    assert_eq!(machine.cpu.bus().ram[0..2], [96, 96]);
+    let mut rewound = 1;
+    while machine.rewind() {
+      rewound += 1;
     }
+    assert_eq!(rewound, 14);
+    assert_eq!(machine.frame_count(), 44);
+
+    // Running forward again picks up from there
+    machine.run_frame();
+    assert_eq!(machine.cpu.bus().ram[0..2], [45, 45]);
+  }
+
+  #[test]
+  fn speed_control() {
+    let mut machine = interrupt_counter();
+    let later = Instant::now() + std::time::Duration::from_secs(60);
+    assert_eq!(machine.speed(), Speed::Multiplier(1.0));
+    assert_eq!(machine.update(later, |_| ()), 1);
+
+    // Quarter speed runs a frame on every fourth update
+    machine.slower();
+    machine.slower();
+    machine.slower();
+    assert_eq!(machine.speed(), Speed::Multiplier(0.25));
+    let counts: Vec<u32> = (0..8).map(|_| machine.update(later, |_| ())).collect();
+    assert_eq!(counts, [0, 0, 0, 1, 0, 0, 0, 1]);
+
+    (0..10).for_each(|_| machine.faster());
+    assert_eq!(machine.speed(), Speed::Multiplier(8.0));
+    let mut seen = Vec::new();
+    assert_eq!(machine.update(later, |machine| seen.push(machine.frame_count())), 8);
+    assert_eq!(seen, (4..12).collect::<Vec<u64>>());
+
+    // Fast-forward is only limited by the deadline, but always makes progress
+    machine.set_fast_forward(true);
+    assert_eq!(machine.speed(), Speed::Unthrottled);
+    assert_eq!(machine.update(Instant::now(), |_| ()), 1);
+    machine.set_fast_forward(false);
+    machine.reset_speed();
+
+    machine.pause();
+    assert_eq!(machine.update(later, |_| ()), 0);
+    machine.advance_frame();
+    assert!(machine.is_paused());
+    assert_eq!(machine.frame_count(), 13);
+    assert_eq!(machine.cpu.bus().ram[0..2], [13, 13]);
+    machine.resume();
+    assert_eq!(machine.update(later, |_| ()), 1);
   }
 }
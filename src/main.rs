@@ -1,174 +1,435 @@
 //#![windows_subsystem = "windows"]
-mod intel8080;
-mod machine;
-
-use machine::{Machine, PlayerKey};
-use ::image::{RgbaImage, Rgba};
+use emulate_8080::audio::RodioAudio;
+use emulate_8080::machine::{Machine, PlayerKey, Speed};
+use emulate_8080::monitor;
+use emulate_8080::movie::Movie;
+use emulate_8080::recorder::GifRecorder;
+use emulate_8080::video::{self, ScreenshotOptions};
+use ::image::RgbaImage;
 use piston_window::*;
+use std::{fs::File, io::BufRead, io::BufWriter, path::PathBuf, sync::mpsc, time::Duration, time::Instant, time::SystemTime};
 
 const SCALE: f64 = 3.0;
+const SAVE_SLOTS: u8 = 10;
+// User-defined overlays are added after the built-in ones
+const OVERLAY_FILE: &str = "overlays.toml";
+const SCREENSHOT_DIRECTORY: &str = "screenshots";
+const RECORDING_DIRECTORY: &str = "recordings";
+const MOVIE_DIRECTORY: &str = "movies";
+const REWIND_SECONDS: u32 = 10;
+const INDICATOR_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 0.8];
+const SLOW_INDICATOR_COLOR: [f32; 4] = [1.0, 0.7, 0.0, 0.8];
 
-fn load_space_invaders(emulator: &mut Machine) -> zip::result::ZipResult<()> {
-  let mut zip = zip::ZipArchive::new(std::io::Cursor::new(include_bytes!("../roms/invaders.zip").to_vec()))?;
-  emulator.load_rom(&mut zip.by_name("invaders.h")?)?;
-  emulator.load_rom_at(&mut zip.by_name("invaders.g")?, 0x800)?;
-  emulator.load_rom_at(&mut zip.by_name("invaders.f")?, 0x1000)?;
-  emulator.load_rom_at(&mut zip.by_name("invaders.e")?, 0x1800)?;
+type Recording = (PathBuf, GifRecorder<BufWriter<File>>);
 
-  Ok(())
+fn save_slot_path(slot: u8) -> PathBuf {
+  PathBuf::from("saves").join(format!("slot{}.sav", slot))
 }
 
-fn main() -> std::io::Result<()> {
-  //TODO: Implement save state with Serde+Flexbuffers?
-  let mut emulator = Machine::new();
+fn save_to_slot(emulator: &Machine, slot: u8) -> std::io::Result<()> {
+  let path = save_slot_path(slot);
+  if let Some(directory) = path.parent() {
+    std::fs::create_dir_all(directory)?;
+  }
+  std::fs::write(path, emulator.save_state())
+}
 
-  #[cfg(feature = "cputest")]
-  {
-    emulator.load_rom("roms/cputest.bin")?;
-    loop {
-      emulator.execute();
+fn load_from_slot(emulator: &mut Machine, slot: u8) -> std::io::Result<()> {
+  emulator.load_state(&std::fs::read(save_slot_path(slot))?)
+}
+
+// Records the screen to a GIF and the sound to a WAV with the same name, which line up because both follow emulated time
+fn start_recording(emulator: &mut Machine) -> std::io::Result<Recording> {
+  std::fs::create_dir_all(RECORDING_DIRECTORY)?;
+  let path = PathBuf::from(RECORDING_DIRECTORY).join(video::timestamped_file_name(SystemTime::now(), "gif"));
+  let recorder = GifRecorder::create(&path, 1)?;
+  emulator.start_wav_recording(path.with_extension("wav"))?;
+
+  Ok((path, recorder))
+}
+
+// Adds the frame that just ran to the recording, if there is one, and stops recording if that fails
+fn record_frame(recording: &mut Option<Recording>, emulator: &mut Machine) {
+  if let Some((_, recorder)) = recording {
+    if let Err(error) = recorder.add_frame(emulator) {
+      println!("Stopped recording: {}", error);
+      *recording = None;
+      emulator.stop_wav_recording().ok();
     }
   }
+  if let Some(error) = emulator.take_wav_error() {
+    println!("Stopped recording audio: {}", error);
+  }
+}
 
-  #[cfg(not(feature = "cputest"))]
-  {
-    let mut window: PistonWindow =
-      WindowSettings::new("Space Invaders", [224. * SCALE, 256. * SCALE])
-        .resizable(false)
-        .exit_on_esc(true)
-        //.graphics_api(OpenGL::V4_5)
-        .graphics_api(OpenGL::V3_2)
-        .samples(16)
-        .build()
-        .unwrap();
-
-    load_space_invaders(&mut emulator).unwrap();
-
-    let mut screen = RgbaImage::new(224, 256);
-    let mut texture_context = window.create_texture_context();
-    let texture_settings = TextureSettings::new();
-    let mut screen_texture = Texture::from_image(&mut texture_context, &screen, &texture_settings).unwrap();
-
-    let background = ::image::load_from_memory(include_bytes!("../images/background.jpg")).unwrap();
-    let background = match background {
-      ::image::DynamicImage::ImageRgba8(image) => image,
-      image => image.to_rgba8(),
+// Pause bars, or arrows in the top right corner when not running at normal speed
+fn draw_indicator<G: Graphics>(paused: bool, rewinding: bool, speed: Speed, transform: math::Matrix2d, graphics: &mut G) {
+  let size = 8.0 * SCALE;
+  let right = video::WIDTH as f64 * SCALE - size;
+  let top = size;
+  let (arrows, color, backwards) = match (rewinding, paused, speed) {
+    (true, _, _) => (2, INDICATOR_COLOR, true),
+    (false, true, _) => {
+      for left in [right - size, right - size * 0.35] {
+        rectangle(INDICATOR_COLOR, [left, top, size * 0.35, size], transform, graphics);
+      }
+      return;
+    }
+    (false, false, Speed::Unthrottled) => (4, INDICATOR_COLOR, false),
+    // One arrow for each doubling or halving of the speed
+    (false, false, Speed::Multiplier(multiplier)) => match multiplier >= 1.0 {
+      true => (multiplier.log2() as usize, INDICATOR_COLOR, false),
+      false => ((1.0 / multiplier).log2() as usize, SLOW_INDICATOR_COLOR, false),
+    },
+  };
+
+  let width = size * 0.6;
+  for index in 0..arrows {
+    let left = right - width * (arrows - index) as f64;
+    let points = match backwards {
+      true => [[left + width, top], [left, top + size / 2.0], [left + width, top + size]],
+      false => [[left, top], [left + width, top + size / 2.0], [left, top + size]],
     };
-    let background = ::image::imageops::resize(&background, 224 * SCALE as u32, 256 * SCALE as u32, ::image::imageops::FilterType::Lanczos3);
-    let background = Texture::from_image(
-      &mut texture_context,
-      &background,
-      &texture_settings,
-    ).unwrap();
-    let mut show_background = false;
-
-    while let Some(event) = window.next() {
-      window.draw_2d(&event, |context, graphics, device| {
-        clear([0.0, 0.0, 0.0, 1.0], graphics);
-
-        // If the background is shown, attempt to make the graphics look more like the arcade projection
-        let alpha = match show_background {
-          true => 0x90,
-          false => 0xFF,
-        };
-
-        let screen_buffer = emulator.frame_buffer();
-        // In the actual Space Invaders machine, the screen is drawn sideways and the monitor is physically rotated 90 CCW
-        // This means the data in memory starts with the bottom left corner
-        for y in 0..256 {
-          for x in 0..224 {
-            let index = (x * 32) + ((255 - y) / 8);
-            let byte = screen_buffer[index as usize];
-            let bit = byte & (1 << (255 - y) % 8);
-            screen.put_pixel(x, y, match bit {
-              0 => match y {
-                //32..=63 => Rgba([0xFF, 0x00, 0x00, 0xFF]),
-                //184..=239 => Rgba([0x00, 0xFF, 0x00, 0xFF]),
-                //240..=255 if x > 23 && x < 136 => Rgba([0x00, 0xFF, 0x00, 0xFF]),
-                _ => Rgba([0x00, 0x00, 0x00, 0x00]),
-              }
-              _ => match y {
-                32..=63 => Rgba([0xFF, 0x00, 0x00, alpha]),
-                184..=239 => Rgba([0x00, 0xFF, 0x00, alpha]),
-                240..=255 if x > 23 && x < 136 => Rgba([0x00, 0xFF, 0x00, alpha]),
-                _ => Rgba([0xFF, 0xFF, 0xFF, alpha]),
-              }
-            });
-          }
-        }
+    polygon(color, &points, transform, graphics);
+  }
+}
 
-        screen_texture.update(&mut texture_context, &screen).unwrap();
-        texture_context.encoder.flush(device);
+fn show_speed(window: &mut PistonWindow, emulator: &Machine) {
+  window.set_title(format!("Space Invaders - {}", emulator.speed()));
+}
 
-        if show_background {
-          image(
-            &background,
-            context.transform,
-            graphics,
-          );
-        }
+fn save_movie(movie: &Movie) -> std::io::Result<PathBuf> {
+  std::fs::create_dir_all(MOVIE_DIRECTORY)?;
+  let path = PathBuf::from(MOVIE_DIRECTORY).join(video::timestamped_file_name(SystemTime::now(), "movie"));
+  movie.save(&path)?;
+
+  Ok(path)
+}
+
+// Movies are named after when they were recorded, so the last one by name is the newest
+fn latest_movie() -> std::io::Result<PathBuf> {
+  let mut paths: Vec<PathBuf> = std::fs::read_dir(MOVIE_DIRECTORY)?
+    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+    .filter(|path| path.extension().is_some_and(|extension| extension == "movie"))
+    .collect();
+  paths.sort();
+  paths.pop().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No movies have been recorded"))
+}
+
+fn load_overlay_profiles() -> Vec<video::Overlay> {
+  let mut overlays = video::Overlay::built_in();
+  match video::load_overlays(OVERLAY_FILE) {
+    Ok(user_overlays) => overlays.extend(user_overlays),
+    Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+    Err(error) => println!("Could not load {}: {}", OVERLAY_FILE, error),
+  }
+
+  overlays
+}
+
+// Reads debugger commands on another thread, so the game keeps running while waiting for them
+fn start_monitor() -> mpsc::Receiver<String> {
+  let (sender, receiver) = mpsc::channel();
+  std::thread::spawn(move || {
+    for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+      if sender.send(line).is_err() {
+        break;
+      }
+    }
+  });
+  println!("Debugger ready, h for help");
+
+  receiver
+}
+
+fn main() -> std::io::Result<()> {
+  let mut emulator = Machine::new();
+  match RodioAudio::new() {
+    Ok(audio) => emulator.set_audio(Box::new(audio)),
+    Err(error) => println!("Running without sound: {}", error),
+  }
+
+  let mut window: PistonWindow =
+    WindowSettings::new("Space Invaders", [video::WIDTH as f64 * SCALE, video::HEIGHT as f64 * SCALE])
+      .resizable(false)
+      .exit_on_esc(true)
+      //.graphics_api(OpenGL::V4_5)
+      .graphics_api(OpenGL::V3_2)
+      .samples(16)
+      .build()
+      .unwrap();
+
+  // One update per emulated frame
+  window.set_ups(60);
+  emulator.set_rewind_seconds(REWIND_SECONDS);
+
+  emulator.load_rom_zip(std::io::Cursor::new(include_bytes!("../roms/invaders.zip"))).unwrap();
+
+  let mut screen = RgbaImage::new(video::WIDTH as u32, video::HEIGHT as u32);
+  let mut texture_context = window.create_texture_context();
+  let texture_settings = TextureSettings::new();
+  let mut screen_texture = Texture::from_image(&mut texture_context, &screen, &texture_settings).unwrap();
+
+  let background = ::image::load_from_memory(include_bytes!("../images/background.jpg")).unwrap();
+  let background = match background {
+    ::image::DynamicImage::ImageRgba8(image) => image,
+    image => image.to_rgba8(),
+  };
+  let background = ::image::imageops::resize(&background, (video::WIDTH as f64 * SCALE) as u32, (video::HEIGHT as f64 * SCALE) as u32, ::image::imageops::FilterType::Lanczos3);
+  let background_texture = Texture::from_image(
+    &mut texture_context,
+    &background,
+    &texture_settings,
+  ).unwrap();
+  let mut show_background = false;
+  let mut save_slot = 0;
+  let overlays = load_overlay_profiles();
+  let mut overlay_index = 0;
+  let mut rewinding = false;
+  let mut recording: Option<Recording> = None;
+  let monitor = match std::env::args().any(|argument| argument == "--debug") {
+    true => Some(start_monitor()),
+    false => None,
+  };
+
+  while let Some(event) = window.next() {
+    window.draw_2d(&event, |context, graphics, device| {
+      clear([0.0, 0.0, 0.0, 1.0], graphics);
+
+      emulator.render_rgba(&mut screen);
+      // If the background is shown, attempt to make the graphics look more like the arcade projection
+      if show_background {
+        video::set_lit_alpha(&mut screen, video::BACKGROUND_ALPHA);
+      }
 
+      screen_texture.update(&mut texture_context, &screen).unwrap();
+      texture_context.encoder.flush(device);
+
+      if show_background {
         image(
-          &screen_texture,
-          context.transform.scale(SCALE, SCALE),
+          &background_texture,
+          context.transform,
           graphics,
         );
-      });
+      }
+
+      image(
+        &screen_texture,
+        context.transform.scale(SCALE, SCALE),
+        graphics,
+      );
+      draw_indicator(emulator.is_paused(), rewinding, emulator.speed(), context.transform, graphics);
+    });
+
+    if let Some(args) = event.update_args() {
+      match rewinding {
+        true => {
+          emulator.rewind();
+        }
+        false => {
+          // Leave the other half of the update for drawing, so fast-forward doesn't starve the window
+          let deadline = Instant::now() + Duration::from_secs_f64(args.dt / 2.0);
+          emulator.update(deadline, |emulator| record_frame(&mut recording, emulator));
+        }
+      }
+    }
 
-      if let Some(_args) = event.update_args() {
-        //TODO: Use args.dt?
-        emulator.execute();
+    if let Some(monitor) = &monitor {
+      for line in monitor.try_iter() {
+        match monitor::execute(&mut emulator, &line) {
+          Ok(output) if output.is_empty() => (),
+          Ok(output) => println!("{}", output),
+          Err(error) => println!("{}", error),
+        }
       }
+      for hit in emulator.take_watch_log() {
+        println!("{}", hit);
+      }
+      if let Some(reason) = emulator.take_break() {
+        println!("{}", monitor::describe_break(&emulator, reason));
+      }
+    }
 
-      if let Some(args) = event.button_args() {
-        match args.button {
-          Button::Keyboard(key) => match key {
-            Key::C => match args.state {
-              ButtonState::Press => emulator.key_down(PlayerKey::Coin),
-              ButtonState::Release => emulator.key_up(PlayerKey::Coin),
+    if let Some(args) = event.button_args() {
+      if let Button::Keyboard(key) = args.button {
+        match key {
+          Key::C => match args.state {
+            ButtonState::Press => emulator.key_down(PlayerKey::Coin),
+            ButtonState::Release => emulator.key_up(PlayerKey::Coin),
+          }
+          Key::T => match args.state {
+              ButtonState::Press => emulator.key_down(PlayerKey::Tilt),
+              ButtonState::Release => emulator.key_up(PlayerKey::Tilt),
+          }
+          Key::D1 => match args.state {
+            ButtonState::Press => emulator.key_down(PlayerKey::P1Start),
+            ButtonState::Release => emulator.key_up(PlayerKey::P1Start),
+          }
+          Key::D2 => match args.state {
+            ButtonState::Press => emulator.key_down(PlayerKey::P2Start),
+            ButtonState::Release => emulator.key_up(PlayerKey::P2Start),
+          }
+          Key::Space => match args.state {
+            ButtonState::Press => emulator.key_down(PlayerKey::P1Fire),
+            ButtonState::Release => emulator.key_up(PlayerKey::P1Fire),
+          }
+          Key::Slash => match args.state {
+            ButtonState::Press => emulator.key_down(PlayerKey::P2Fire),
+            ButtonState::Release => emulator.key_up(PlayerKey::P2Fire),
+          }
+          Key::Left => match args.state {
+            ButtonState::Press => emulator.key_down(PlayerKey::P2Left),
+            ButtonState::Release => emulator.key_up(PlayerKey::P2Left),
+          }
+          Key::Right => match args.state {
+            ButtonState::Press => emulator.key_down(PlayerKey::P2Right),
+            ButtonState::Release => emulator.key_up(PlayerKey::P2Right),
+          }
+          Key::A => match args.state {
+            ButtonState::Press => emulator.key_down(PlayerKey::P1Left),
+            ButtonState::Release => emulator.key_up(PlayerKey::P1Left),
+          }
+          Key::D => match args.state {
+            ButtonState::Press => emulator.key_down(PlayerKey::P1Right),
+            ButtonState::Release => emulator.key_up(PlayerKey::P1Right),
+          }
+          Key::B => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => show_background = !show_background,
+          }
+          Key::O => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => {
+              overlay_index = (overlay_index + 1) % overlays.len();
+              emulator.set_overlay(overlays[overlay_index].clone());
+              window.set_title(format!("Space Invaders - {}", overlays[overlay_index].name));
+            }
+          }
+          Key::F12 => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => {
+              // Capture the screen as it is shown in the window
+              let options = ScreenshotOptions {
+                overlay: true,
+                scale: SCALE as u32,
+                background: match show_background {
+                  true => Some(&background),
+                  false => None,
+                },
+              };
+              match emulator.save_screenshot(SCREENSHOT_DIRECTORY, &options) {
+                Ok(path) => println!("Saved screenshot to {}", path.display()),
+                Err(error) => println!("Could not save screenshot: {}", error),
+              }
+            }
+          }
+          Key::R => rewinding = match args.state {
+            ButtonState::Press => true,
+            ButtonState::Release => false,
+          },
+          Key::P => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => match emulator.is_paused() {
+              true => emulator.resume(),
+              false => emulator.pause(),
             }
-            Key::T => match args.state {
-                ButtonState::Press => emulator.key_down(PlayerKey::Tilt),
-                ButtonState::Release => emulator.key_up(PlayerKey::Tilt),
+          }
+          Key::Period => match args.state {
+            ButtonState::Press => {
+              emulator.advance_frame();
+              record_frame(&mut recording, &mut emulator);
             }
-            Key::D1 => match args.state {
-              ButtonState::Press => emulator.key_down(PlayerKey::P1Start),
-              ButtonState::Release => emulator.key_up(PlayerKey::P1Start),
+            ButtonState::Release => (),
+          }
+          Key::Minus => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => {
+              emulator.slower();
+              show_speed(&mut window, &emulator);
             }
-            Key::D2 => match args.state {
-              ButtonState::Press => emulator.key_down(PlayerKey::P2Start),
-              ButtonState::Release => emulator.key_up(PlayerKey::P2Start),
+          }
+          Key::Equals => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => {
+              emulator.faster();
+              show_speed(&mut window, &emulator);
             }
-            Key::Space => match args.state {
-              ButtonState::Press => emulator.key_down(PlayerKey::P1Fire),
-              ButtonState::Release => emulator.key_up(PlayerKey::P1Fire),
+          }
+          Key::D0 => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => {
+              emulator.reset_speed();
+              show_speed(&mut window, &emulator);
             }
-            Key::Slash => match args.state {
-              ButtonState::Press => emulator.key_down(PlayerKey::P2Fire),
-              ButtonState::Release => emulator.key_up(PlayerKey::P2Fire),
+          }
+          Key::Tab => match args.state {
+            ButtonState::Press => emulator.set_fast_forward(true),
+            ButtonState::Release => emulator.set_fast_forward(false),
+          }
+          Key::F2 => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => match emulator.stop_movie_recording() {
+              Some(movie) => match save_movie(&movie) {
+                Ok(path) => println!("Saved movie to {}", path.display()),
+                Err(error) => println!("Could not save movie: {}", error),
+              }
+              None => {
+                emulator.start_movie_recording();
+                println!("Recording movie");
+              }
             }
-            Key::Left => match args.state {
-              ButtonState::Press => emulator.key_down(PlayerKey::P2Left),
-              ButtonState::Release => emulator.key_up(PlayerKey::P2Left),
+          }
+          Key::F3 => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => match emulator.is_playing_movie() {
+              true => {
+                emulator.stop_movie_playback();
+                println!("Stopped movie playback");
+              }
+              false => match latest_movie().and_then(|path| emulator.play_movie(Movie::load(&path)?).map(|()| path)) {
+                Ok(path) => println!("Playing movie {}", path.display()),
+                Err(error) => println!("Could not play movie: {}", error),
+              }
             }
-            Key::Right => match args.state {
-              ButtonState::Press => emulator.key_down(PlayerKey::P2Right),
-              ButtonState::Release => emulator.key_up(PlayerKey::P2Right),
+          }
+          Key::F9 => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => match recording.take() {
+              // Dropping the recorder finishes the file
+              Some((path, _)) => match emulator.stop_wav_recording() {
+                Ok(()) => println!("Saved recording to {}", path.display()),
+                Err(error) => println!("Could not save the sound for {}: {}", path.display(), error),
+              }
+              None => match start_recording(&mut emulator) {
+                Ok((path, recorder)) => {
+                  println!("Recording to {}", path.display());
+                  recording = Some((path, recorder));
+                }
+                Err(error) => println!("Could not start recording: {}", error),
+              }
             }
-            Key::A => match args.state {
-              ButtonState::Press => emulator.key_down(PlayerKey::P1Left),
-              ButtonState::Release => emulator.key_up(PlayerKey::P1Left),
+          }
+          Key::F5 => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => match save_to_slot(&emulator, save_slot) {
+              Ok(()) => println!("Saved state to slot {}", save_slot),
+              Err(error) => println!("Could not save state to slot {}: {}", save_slot, error),
             }
-            Key::D => match args.state {
-              ButtonState::Press => emulator.key_down(PlayerKey::P1Right),
-              ButtonState::Release => emulator.key_up(PlayerKey::P1Right),
+          }
+          Key::F6 => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => {
+              save_slot = (save_slot + 1) % SAVE_SLOTS;
+              window.set_title(format!("Space Invaders - Slot {}", save_slot));
             }
-            Key::B => match args.state {
-              ButtonState::Press => (),
-              ButtonState::Release => show_background = !show_background,
+          }
+          Key::F7 => match args.state {
+            ButtonState::Press => (),
+            ButtonState::Release => match load_from_slot(&mut emulator, save_slot) {
+              Ok(()) => println!("Loaded state from slot {}", save_slot),
+              Err(error) => println!("Could not load state from slot {}: {}", save_slot, error),
             }
-            _ => ()
-          },
+          }
           _ => ()
         }
       }
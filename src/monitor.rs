@@ -0,0 +1,325 @@
+use std::fmt::Write;
+
+use crate::debugger::{parse_hex, Access, BreakReason, Breakpoint, WatchAction, Watchpoint};
+use crate::intel8080::Interrupts;
+use crate::machine::Machine;
+
+// A line-based debugger front-end: each command is given the machine, which keeps running between commands, and
+// returns the text to show for it
+
+const DISASSEMBLY_LINES: u16 = 10;
+const DISASSEMBLY_CONTEXT: u16 = 3;
+const MEMORY_DUMP_LENGTH: u16 = 0x40;
+const BYTES_PER_ROW: u16 = 16;
+
+const HELP: &str = "\
+r                         registers, and the code around PC
+d [address] [count]       disassemble
+m <address> [length]      dump memory
+b <address> [if <condition>]  set a breakpoint, such as: b 0a1c if a == 3f
+bd <address>              delete the breakpoints at an address
+bl                        list breakpoints
+w <address>[-<end>] [r|w|rw] [log]  stop after, or log, reads and writes of memory, such as: w 20f8-20f9 w log
+wd <address>              delete the watchpoints covering an address
+wl                        list watchpoints
+s                         step into
+n                         step over
+o                         step out
+g <address>               run to an address
+c                         continue
+p                         pause";
+
+pub fn execute(machine: &mut Machine, line: &str) -> Result<String, String> {
+  let mut words = line.split_whitespace();
+  let command = match words.next() {
+    Some(command) => command,
+    None => return Ok(String::new()),
+  };
+  let arguments: Vec<&str> = words.collect();
+  let address = |index: usize| match arguments.get(index) {
+    Some(text) => parse_hex(text),
+    None => Err(format!("{} needs an address", command)),
+  };
+
+  match command {
+    "h" | "help" | "?" => Ok(HELP.to_string()),
+    "r" => Ok(status(machine)),
+    "d" => {
+      let start = match arguments.first() {
+        Some(_) => address(0)?,
+        None => machine.registers().pc,
+      };
+      let count = arguments.get(1).map_or(Ok(DISASSEMBLY_LINES), |text| parse_hex(text))?;
+      Ok(disassembly(machine, start, count))
+    }
+    "m" => {
+      let length = arguments.get(1).map_or(Ok(MEMORY_DUMP_LENGTH), |text| parse_hex(text))?;
+      Ok(memory_dump(machine, address(0)?, length))
+    }
+    "b" => {
+      let breakpoint = match arguments.get(1) {
+        Some(&"if") => Breakpoint::when(address(0)?, arguments[2..].join(" ").parse()?),
+        Some(_) => return Err("Conditions start with if".to_string()),
+        None => Breakpoint::new(address(0)?),
+      };
+      machine.add_breakpoint(breakpoint);
+      Ok(format!("Breakpoint at {}", breakpoint))
+    }
+    "bd" => match machine.remove_breakpoint(address(0)?) {
+      true => Ok("Deleted".to_string()),
+      false => Err("No breakpoint there".to_string()),
+    },
+    "bl" => Ok(machine.breakpoints().iter().map(|breakpoint| breakpoint.to_string()).collect::<Vec<String>>().join("\n")),
+    "w" => {
+      let watchpoint = watchpoint(&arguments)?;
+      machine.add_watchpoint(watchpoint);
+      Ok(format!("Watchpoint at {}", watchpoint))
+    }
+    "wd" => match machine.remove_watchpoint(address(0)?) {
+      true => Ok("Deleted".to_string()),
+      false => Err("No watchpoint there".to_string()),
+    },
+    "wl" => Ok(machine.watchpoints().iter().map(|watchpoint| watchpoint.to_string()).collect::<Vec<String>>().join("\n")),
+    "s" => {
+      machine.step_into();
+      Ok(String::new())
+    }
+    "n" => {
+      machine.step_over();
+      Ok(String::new())
+    }
+    "o" => {
+      machine.step_out();
+      Ok(String::new())
+    }
+    "g" => {
+      machine.run_to(address(0)?);
+      Ok(String::new())
+    }
+    "c" => {
+      machine.resume();
+      Ok(String::new())
+    }
+    "p" => {
+      machine.pause();
+      Ok(status(machine))
+    }
+    _ => Err(format!("Unknown command {}, h for help", command)),
+  }
+}
+
+fn watchpoint(arguments: &[&str]) -> Result<Watchpoint, String> {
+  let range = arguments.first().ok_or("w needs an address")?;
+  let (start, end) = match range.split_once('-') {
+    Some((start, end)) => (parse_hex(start)?, parse_hex(end)?),
+    None => (parse_hex(range)?, parse_hex(range)?),
+  };
+  let mut access = Access::ReadWrite;
+  let mut action = WatchAction::Break;
+  for argument in &arguments[1..] {
+    match *argument {
+      "r" => access = Access::Read,
+      "w" => access = Access::Write,
+      "rw" => access = Access::ReadWrite,
+      "log" => action = WatchAction::Log,
+      _ => return Err(format!("Unknown watchpoint option {}", argument)),
+    }
+  }
+
+  Ok(Watchpoint::new(start, end, access, action))
+}
+
+// What to show when the debugger stops the machine
+pub fn describe_break(machine: &Machine, reason: BreakReason) -> String {
+  match reason {
+    BreakReason::Breakpoint(breakpoint) => format!("Breakpoint at {}\n{}", breakpoint, status(machine)),
+    BreakReason::Watchpoint(hit) => format!("Watchpoint {}\n{}", hit, status(machine)),
+    BreakReason::Step => status(machine),
+  }
+}
+
+pub fn status(machine: &Machine) -> String {
+  let registers = machine.registers();
+  let flags = [
+    (registers.flags.s, 'S'),
+    (registers.flags.z, 'Z'),
+    (registers.flags.ac, 'A'),
+    (registers.flags.p, 'P'),
+    (registers.flags.cy, 'C'),
+  ].iter().map(|&(set, name)| match set {
+    true => name,
+    false => '-',
+  }).collect::<String>();
+  let mut text = format!(
+    "A={:0>2X} BC={:0>4X} DE={:0>4X} HL={:0>4X} SP={:0>4X} PC={:0>4X} {} {}{}\n",
+    registers.a,
+    registers.bc(),
+    registers.de(),
+    registers.hl(),
+    registers.sp,
+    registers.pc,
+    flags,
+    match registers.interrupts {
+      Interrupts::Disabled => "DI",
+      // Interrupts are accepted after the next instruction
+      Interrupts::PreEnabled => "EI pending",
+      Interrupts::Enabled => "EI",
+    },
+    match registers.halted {
+      true => " HALT",
+      false => "",
+    },
+  );
+  let start = start_before(machine, registers.pc, DISASSEMBLY_CONTEXT);
+  text.push_str(&disassembly(machine, start, DISASSEMBLY_LINES));
+
+  text
+}
+
+// Marks the next instruction to run with > and breakpoints with *
+pub fn disassembly(machine: &Machine, start: u16, count: u16) -> String {
+  let pc = machine.registers().pc;
+  let mut address = start;
+  let mut lines = Vec::new();
+  for _ in 0..count {
+    let (instruction, length) = machine.disassemble(address);
+    let bytes: Vec<String> = (0..length).map(|offset| format!("{:0>2X}", machine.read_memory(address.wrapping_add(offset)))).collect();
+    let marker = match (address == pc, machine.breakpoints().iter().any(|breakpoint| breakpoint.address == address)) {
+      (true, _) => '>',
+      (false, true) => '*',
+      (false, false) => ' ',
+    };
+    lines.push(format!("{} {:0>4X}  {:<9} {}", marker, address, bytes.join(" "), instruction));
+    address = address.wrapping_add(length);
+  }
+
+  lines.join("\n")
+}
+
+// Instructions can't be decoded backwards, so this looks for the earliest address that decodes into no more than the
+// given number of instructions ending exactly at the address
+fn start_before(machine: &Machine, address: u16, count: u16) -> u16 {
+  for start in address.saturating_sub(count * 3)..address {
+    // An instruction can run past 0xFFFF, so this is counted in u32
+    let mut next = start as u32;
+    let mut instructions = 0;
+    while next < address as u32 {
+      next += machine.disassemble(next as u16).1 as u32;
+      instructions += 1;
+    }
+    if next == address as u32 && instructions <= count {
+      return start;
+    }
+  }
+
+  address
+}
+
+pub fn memory_dump(machine: &Machine, start: u16, length: u16) -> String {
+  let mut text = String::new();
+  for row in (0..length).step_by(BYTES_PER_ROW as usize) {
+    let address = start.wrapping_add(row);
+    let bytes: Vec<u8> = (0..BYTES_PER_ROW.min(length - row)).map(|offset| machine.read_memory(address.wrapping_add(offset))).collect();
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:0>2X}", byte)).collect();
+    let ascii: String = bytes.iter().map(|&byte| match byte.is_ascii_graphic() || byte == b' ' {
+      true => byte as char,
+      false => '.',
+    }).collect();
+    writeln!(text, "{:0>4X}  {:<47}  {}", address, hex.join(" "), ascii).expect("Writing to a string can't fail");
+  }
+  text.pop();
+
+  text
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // LXI SP, $2400; CALL $0010; JMP $0003, then a subroutine that counts in $2000
+  fn machine() -> Machine {
+    let mut machine = Machine::new();
+    let mut rom = vec![0; 0x20];
+    rom[..9].copy_from_slice(&[0x31, 0x00, 0x24, 0xCD, 0x10, 0x00, 0xC3, 0x03, 0x00]);
+    rom[0x10..0x18].copy_from_slice(&[0x3A, 0x00, 0x20, 0x3C, 0x32, 0x00, 0x20, 0xC9]);
+    machine.load_rom_bytes(&rom);
+    machine
+  }
+
+  // Runs until the debugger stops the machine, the way the front-end does
+  fn run(machine: &mut Machine) -> String {
+    for _ in 0..10 {
+      if machine.run_frame().is_some() {
+        let reason = machine.take_break().unwrap();
+        return describe_break(machine, reason);
+      }
+    }
+    panic!("The debugger never stopped");
+  }
+
+  #[test]
+  fn commands() {
+    let mut machine = machine();
+    assert_eq!(execute(&mut machine, "b 13 if a == 2").unwrap(), "Breakpoint at 0013 if a == 2");
+    let report = run(&mut machine);
+    assert!(report.starts_with("Breakpoint at 0013 if a == 2\nA=02 BC=0000 DE=0000 HL=0000 SP=23FE PC=0013 "), "{}", report);
+    assert!(machine.is_paused());
+    assert!(report.contains("  0010  3A 00 20  LDA    $2000\n> 0013  3C        INR    A\n  0014  32 00 20  STA    $2000\n"), "{}", report);
+
+    execute(&mut machine, "s").unwrap();
+    assert!(run(&mut machine).starts_with("A=03 "));
+    execute(&mut machine, "o").unwrap();
+    assert!(run(&mut machine).contains("SP=2400 PC=0006 "));
+    execute(&mut machine, "n").unwrap();
+    assert!(run(&mut machine).contains("PC=0003 "));
+    // Stepping over the call stops at the breakpoint only if its condition holds
+    execute(&mut machine, "n").unwrap();
+    assert!(run(&mut machine).contains("PC=0006 "));
+    assert_eq!(machine.read_memory(0x2000), 4);
+
+    assert_eq!(execute(&mut machine, "bl").unwrap(), "0013 if a == 2");
+    execute(&mut machine, "bd 13").unwrap();
+    assert!(execute(&mut machine, "bd 13").is_err());
+    execute(&mut machine, "g 0x0017").unwrap();
+    assert!(run(&mut machine).contains("PC=0017 "));
+
+    assert_eq!(
+      execute(&mut machine, "m 2000 12").unwrap(),
+      "2000  05 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00  ................\n2010  00 00                                            .."
+    );
+    assert!(execute(&mut machine, "b").is_err());
+    assert!(execute(&mut machine, "b 10 when a == 1").is_err());
+    assert!(execute(&mut machine, "x").is_err());
+
+    // Looking for the instructions before the PC doesn't overflow at the top of the address space
+    machine.write_memory(0xFFFE, 0xC3); // JMP
+    let mut registers = machine.registers();
+    registers.pc = 0xFFFF;
+    machine.set_registers(&registers);
+    assert!(execute(&mut machine, "r").unwrap().contains("> FFFF "));
+  }
+
+  #[test]
+  fn watchpoints() {
+    let mut machine = machine();
+    assert_eq!(execute(&mut machine, "w 2000-2001 w log").unwrap(), "Watchpoint at 2000-2001 w log");
+    assert_eq!(execute(&mut machine, "w 2000 r").unwrap(), "Watchpoint at 2000 r");
+    assert_eq!(execute(&mut machine, "wl").unwrap(), "2000-2001 w log\n2000 r");
+
+    // The read stops execution once the LDA has finished
+    let report = run(&mut machine);
+    assert!(report.starts_with("Watchpoint 0010: read 2000 = 00\nA=00 BC=0000 DE=0000 HL=0000 SP=23FE PC=0013 "), "{}", report);
+    assert!(machine.take_watch_log().is_empty());
+    execute(&mut machine, "wd 2000").unwrap();
+    assert_eq!(execute(&mut machine, "wl").unwrap(), "");
+    execute(&mut machine, "w 2000 w log").unwrap();
+    execute(&mut machine, "w 23fe-23ff w").unwrap();
+    assert!(run(&mut machine).starts_with("Watchpoint 0003: wrote 23FF = 00\n"));
+    let log: Vec<String> = machine.take_watch_log().iter().map(|hit| hit.to_string()).collect();
+    assert_eq!(log, ["0014: wrote 2000 = 01"]);
+
+    assert!(execute(&mut machine, "wd 2100").is_err());
+    assert!(execute(&mut machine, "w 2000 x").is_err());
+    assert!(execute(&mut machine, "w").is_err());
+  }
+}
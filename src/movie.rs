@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+use std::{io::Error, io::ErrorKind};
+
+use crate::machine::PlayerKey;
+
+// Movies start with this, followed by the format version as a little-endian u32 and then the movie as a flexbuffer
+const MOVIE_MAGIC: &[u8; 4] = b"SI8M";
+const MOVIE_VERSION: u32 = 1;
+
+// A key being pressed or released just before the given frame runs, counting from the start of the movie
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputEvent {
+  pub frame: u64,
+  pub key: PlayerKey,
+  pub pressed: bool,
+}
+
+// Everything needed to reproduce a run exactly: where it started, and every input after that
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Movie {
+  // Identifies the ROM set and DIP switch settings the movie was recorded with
+  pub configuration_hash: u32,
+  // A save state of the machine when recording started
+  #[serde(with = "serde_bytes")]
+  pub start_state: Vec<u8>,
+  pub events: Vec<InputEvent>,
+  // How many frames were recorded, so playback knows when the movie ends
+  pub length: u64,
+}
+
+impl Movie {
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut data = MOVIE_MAGIC.to_vec();
+    data.extend_from_slice(&MOVIE_VERSION.to_le_bytes());
+    data.extend_from_slice(&flexbuffers::to_vec(self).expect("Movies should always be serializable"));
+
+    data
+  }
+
+  pub fn from_bytes(data: &[u8]) -> std::io::Result<Self> {
+    if data.len() < 8 || &data[0..4] != MOVIE_MAGIC {
+      return Err(Error::new(ErrorKind::InvalidData, "Not a movie"));
+    }
+    let version = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    if version != MOVIE_VERSION {
+      return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported movie version {}", version)));
+    }
+
+    flexbuffers::from_slice(&data[8..]).map_err(|error| Error::new(ErrorKind::InvalidData, error))
+  }
+
+  pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+    std::fs::write(path, self.to_bytes())
+  }
+
+  pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+    Self::from_bytes(&std::fs::read(path)?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trip() {
+    let movie = Movie {
+      configuration_hash: 0x12345678,
+      start_state: vec![1, 2, 3],
+      events: vec![
+        InputEvent { frame: 10, key: PlayerKey::Coin, pressed: true },
+        InputEvent { frame: 12, key: PlayerKey::Coin, pressed: false },
+      ],
+      length: 100,
+    };
+    let data = movie.to_bytes();
+    assert_eq!(Movie::from_bytes(&data).unwrap(), movie);
+
+    assert!(Movie::from_bytes(b"SI80\x01\x00\x00\x00").is_err());
+    let mut future = data.clone();
+    future[4] = 0xFF;
+    assert!(Movie::from_bytes(&future).is_err());
+  }
+}
@@ -0,0 +1,86 @@
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame};
+use std::{fs::File, io::BufWriter, io::Error, io::Write, path::Path};
+
+use crate::machine::{Machine, FRAMES_PER_SECOND};
+use crate::video::ScreenshotOptions;
+
+// GIF delays are in hundredths of a second, and many viewers slow down anything shorter than 2
+// Keeping every second frame gives delays of 3 or 4, which still add up to exactly one second per 60 emulated frames
+const FRAMES_PER_IMAGE: u64 = 2;
+
+// Records emulated frames into an animated GIF; the file is complete once the recorder is dropped
+pub struct GifRecorder<W: Write> {
+  encoder: GifEncoder<W>,
+  scale: u32,
+  // Emulated frames recorded so far
+  frames: u64,
+}
+
+impl GifRecorder<BufWriter<File>> {
+  pub fn create(path: impl AsRef<Path>, scale: u32) -> std::io::Result<Self> {
+    Self::new(BufWriter::new(File::create(path)?), scale)
+  }
+}
+
+impl<W: Write> GifRecorder<W> {
+  pub fn new(writer: W, scale: u32) -> std::io::Result<Self> {
+    let mut encoder = GifEncoder::new_with_speed(writer, 30);
+    encoder.set_repeat(Repeat::Infinite).map_err(Error::other)?;
+
+    Ok(GifRecorder {
+      encoder,
+      scale,
+      frames: 0,
+    })
+  }
+
+  // Call once after each emulated frame, so the animation runs at the emulated speed regardless of the host
+  pub fn add_frame(&mut self, machine: &Machine) -> std::io::Result<()> {
+    if self.frames.is_multiple_of(FRAMES_PER_IMAGE) {
+      // The delay is worked out from the total time so the rounding never accumulates
+      let start = self.frames * 100 / FRAMES_PER_SECOND as u64;
+      let end = (self.frames + FRAMES_PER_IMAGE) * 100 / FRAMES_PER_SECOND as u64;
+      let image = machine.screenshot(&ScreenshotOptions {
+        scale: self.scale,
+        ..Default::default()
+      });
+      let delay = Delay::from_numer_denom_ms((end - start) as u32 * 10, 1);
+      self.encoder.encode_frame(Frame::from_parts(image, 0, 0, delay)).map_err(Error::other)?;
+    }
+    self.frames += 1;
+
+    Ok(())
+  }
+
+  pub fn frame_count(&self) -> u64 {
+    self.frames
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::AnimationDecoder;
+  use image::codecs::gif::GifDecoder;
+
+  #[test]
+  fn one_second_of_animation_per_60_frames() {
+    let mut data = Vec::new();
+    {
+      let machine = Machine::new();
+      let mut recorder = GifRecorder::new(&mut data, 1).unwrap();
+      for _ in 0..60 {
+        recorder.add_frame(&machine).unwrap();
+      }
+      assert_eq!(recorder.frame_count(), 60);
+    }
+
+    let frames = GifDecoder::new(&data[..]).unwrap().into_frames().collect_frames().unwrap();
+    assert_eq!(frames.len(), 30);
+    assert_eq!(frames[0].buffer().dimensions(), (224, 256));
+    let delays: Vec<u32> = frames.iter().map(|frame| frame.delay().numer_denom_ms().0).collect();
+    assert_eq!(delays[0..3], [30, 30, 40]);
+    assert_eq!(delays.iter().sum::<u32>(), 1000);
+  }
+}
@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+
+// A ring buffer of save states for running the game backwards
+// Only the newest state is kept whole; each older one is stored as the difference from the state after it, which is
+// mostly zeros because little of the machine changes in a few frames, so the runs of zeros are compressed away
+pub struct RewindBuffer {
+  latest: Option<Vec<u8>>,
+  deltas: VecDeque<Vec<u8>>,
+  capacity: usize,
+}
+
+impl RewindBuffer {
+  pub fn new(capacity: usize) -> Self {
+    RewindBuffer {
+      latest: None,
+      deltas: VecDeque::new(),
+      capacity: capacity.max(1),
+    }
+  }
+
+  pub fn push(&mut self, state: Vec<u8>) {
+    if let Some(latest) = self.latest.take() {
+      self.deltas.push_back(encode_delta(&state, &latest));
+    }
+    self.latest = Some(state);
+    // The oldest delta is only needed to get to the oldest state, so it can simply be dropped
+    while self.len() > self.capacity {
+      self.deltas.pop_front();
+    }
+  }
+
+  // Takes the newest state out of the buffer
+  pub fn pop(&mut self) -> Option<Vec<u8>> {
+    let latest = self.latest.take()?;
+    self.latest = self.deltas.pop_back().map(|delta| decode_delta(&latest, &delta));
+
+    Some(latest)
+  }
+
+  pub fn len(&self) -> usize {
+    match self.latest {
+      Some(_) => self.deltas.len() + 1,
+      None => 0,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.latest.is_none()
+  }
+
+  // How many bytes the buffer is using for states, to see how well the deltas are compressing
+  pub fn stored_bytes(&self) -> usize {
+    self.latest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+  }
+}
+
+// The delta is the length of the target, followed by the XOR of the two states as pairs of little-endian u16 counts,
+// the number of zero bytes to skip and then the number of literal bytes that follow
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+  let xor: Vec<u8> = to.iter().enumerate().map(|(index, byte)| byte ^ from.get(index).unwrap_or(&0)).collect();
+  let mut delta = (to.len() as u32).to_le_bytes().to_vec();
+  let mut index = 0;
+  while index < xor.len() {
+    let zeros = xor[index..].iter().take(u16::MAX as usize).take_while(|&&byte| byte == 0).count();
+    index += zeros;
+    let literals = xor[index..].iter().take(u16::MAX as usize).take_while(|&&byte| byte != 0).count();
+    delta.extend_from_slice(&(zeros as u16).to_le_bytes());
+    delta.extend_from_slice(&(literals as u16).to_le_bytes());
+    delta.extend_from_slice(&xor[index..index + literals]);
+    index += literals;
+  }
+
+  delta
+}
+
+fn decode_delta(from: &[u8], delta: &[u8]) -> Vec<u8> {
+  let length = u32::from_le_bytes([delta[0], delta[1], delta[2], delta[3]]) as usize;
+  let mut to: Vec<u8> = (0..length).map(|index| *from.get(index).unwrap_or(&0)).collect();
+  let mut index = 0;
+  let mut position = 4;
+  while position < delta.len() {
+    let zeros = u16::from_le_bytes([delta[position], delta[position + 1]]) as usize;
+    let literals = u16::from_le_bytes([delta[position + 2], delta[position + 3]]) as usize;
+    position += 4;
+    index += zeros;
+    for byte in &delta[position..position + literals] {
+      to[index] ^= byte;
+      index += 1;
+    }
+    position += literals;
+  }
+
+  to
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn deltas() {
+    let from = vec![1, 2, 3, 4, 5, 0, 0, 7];
+    for to in [vec![1, 2, 3, 4, 5, 0, 0, 7], vec![1, 9, 3, 4, 5, 6, 0, 8], vec![1, 2], vec![1, 2, 3, 4, 5, 0, 0, 7, 9, 10], vec![]] {
+      let delta = encode_delta(&from, &to);
+      assert_eq!(decode_delta(&from, &delta), to);
+    }
+    // Identical states only need the length and a single count pair
+    assert_eq!(encode_delta(&from, &from).len(), 8);
+
+    // Runs longer than a u16 can count
+    let from = vec![0; 70000];
+    let mut to = vec![0xFF; 70000];
+    to[1] = 0;
+    assert_eq!(decode_delta(&from, &encode_delta(&from, &to)), to);
+  }
+
+  #[test]
+  fn newest_first() {
+    let state = |value: u8, length: usize| {
+      let mut state = vec![0xAA; length];
+      state[10] = value;
+      state
+    };
+    let mut buffer = RewindBuffer::new(3);
+    assert!(buffer.pop().is_none());
+    for value in 0..5 {
+      buffer.push(state(value, 100));
+    }
+    assert_eq!(buffer.len(), 3);
+    // One whole state, and two deltas that only hold a single byte
+    assert_eq!(buffer.stored_bytes(), 100 + 2 * 13);
+    assert_eq!(buffer.pop(), Some(state(4, 100)));
+    assert_eq!(buffer.pop(), Some(state(3, 100)));
+    buffer.push(state(7, 50));
+    assert_eq!(buffer.pop(), Some(state(7, 50)));
+    assert_eq!(buffer.pop(), Some(state(2, 100)));
+    assert!(buffer.pop().is_none());
+    assert!(buffer.is_empty());
+  }
+}
@@ -0,0 +1,300 @@
+use image::{imageops, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::{io::Error, io::ErrorKind, path::Path, path::PathBuf, time::SystemTime};
+
+// The screen as the player sees it, after the monitor has been rotated upright
+pub const WIDTH: usize = 224;
+pub const HEIGHT: usize = 256;
+// How opaque lit pixels are when drawn over the background, so it shows through like the arcade projection
+pub const BACKGROUND_ALPHA: u8 = 0x90;
+
+// A rectangle of colored cellophane stuck over part of the screen
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverlayRegion {
+  pub x: usize,
+  pub y: usize,
+  pub width: usize,
+  pub height: usize,
+  pub color: [u8; 3],
+}
+
+impl OverlayRegion {
+  fn contains(&self, x: usize, y: usize) -> bool {
+    x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+  }
+}
+
+// The colors that lit pixels are drawn with; the first region containing a pixel decides its color
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Overlay {
+  pub name: String,
+  pub color: [u8; 3],
+  #[serde(default, rename = "region")]
+  pub regions: Vec<OverlayRegion>,
+}
+
+// The layout of an overlay file, which holds any number of [[overlay]] tables
+#[derive(Deserialize)]
+struct OverlayFile {
+  #[serde(default)]
+  overlay: Vec<Overlay>,
+}
+
+impl Default for Overlay {
+  fn default() -> Self {
+    Self::midway()
+  }
+}
+
+impl Overlay {
+  // The original cabinet: red across the top for the UFO, and green at the bottom for the shields and the player
+  // Only the part of the bottom row with the remaining lives is green, the credits stay white
+  pub fn midway() -> Self {
+    Overlay {
+      name: "Midway".to_string(),
+      color: [0xFF, 0xFF, 0xFF],
+      regions: vec![
+        OverlayRegion { x: 0, y: 32, width: WIDTH, height: 32, color: [0xFF, 0x00, 0x00] },
+        OverlayRegion { x: 0, y: 184, width: WIDTH, height: 56, color: [0x00, 0xFF, 0x00] },
+        OverlayRegion { x: 24, y: 240, width: 112, height: 16, color: [0x00, 0xFF, 0x00] },
+      ],
+    }
+  }
+
+  // An approximation of the color version Taito released in Japan, with each group of rows tinted differently
+  pub fn taito() -> Self {
+    Overlay {
+      name: "Taito".to_string(),
+      color: [0xFF, 0xFF, 0xFF],
+      regions: vec![
+        OverlayRegion { x: 0, y: 0, width: WIDTH, height: 32, color: [0x00, 0xFF, 0xFF] },
+        OverlayRegion { x: 0, y: 32, width: WIDTH, height: 32, color: [0xFF, 0x00, 0x00] },
+        OverlayRegion { x: 0, y: 64, width: WIDTH, height: 56, color: [0xFF, 0x00, 0xFF] },
+        OverlayRegion { x: 0, y: 120, width: WIDTH, height: 64, color: [0xFF, 0xFF, 0x00] },
+        OverlayRegion { x: 0, y: 184, width: WIDTH, height: 56, color: [0x00, 0xFF, 0x00] },
+        OverlayRegion { x: 24, y: 240, width: 112, height: 16, color: [0x00, 0xFF, 0x00] },
+      ],
+    }
+  }
+
+  pub fn monochrome() -> Self {
+    Overlay {
+      name: "Monochrome".to_string(),
+      color: [0xFF, 0xFF, 0xFF],
+      regions: Vec::new(),
+    }
+  }
+
+  pub fn built_in() -> Vec<Self> {
+    vec![Self::midway(), Self::taito(), Self::monochrome()]
+  }
+
+  pub fn color_at(&self, x: usize, y: usize) -> [u8; 3] {
+    self.regions.iter()
+      .find(|region| region.contains(x, y))
+      .map_or(self.color, |region| region.color)
+  }
+}
+
+// Reads user-defined overlays from TOML, in the form:
+//   [[overlay]]
+//   name = "Blue"
+//   color = [0, 0, 255]
+//   [[overlay.region]]
+//   x = 0
+//   y = 32
+//   width = 224
+//   height = 32
+//   color = [255, 0, 0]
+pub fn parse_overlays(text: &str) -> std::io::Result<Vec<Overlay>> {
+  let file: OverlayFile = toml::from_str(text).map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+  Ok(file.overlay)
+}
+
+pub fn load_overlays(path: impl AsRef<Path>) -> std::io::Result<Vec<Overlay>> {
+  parse_overlays(&std::fs::read_to_string(path)?)
+}
+
+// Converts the video RAM into a WIDTH x HEIGHT RGBA image, with unlit pixels left transparent
+pub fn render_rgba(frame_buffer: &[u8], overlay: &Overlay, buffer: &mut [u8]) {
+  assert_eq!(buffer.len(), WIDTH * HEIGHT * 4, "The buffer must hold a {}x{} RGBA image", WIDTH, HEIGHT);
+
+  // In the actual Space Invaders machine, the screen is drawn sideways and the monitor is physically rotated 90 CCW
+  // This means the data in memory starts with the bottom left corner
+  for y in 0..HEIGHT {
+    for x in 0..WIDTH {
+      let index = (x * 32) + ((HEIGHT - 1 - y) / 8);
+      let bit = frame_buffer[index] & (1 << ((HEIGHT - 1 - y) % 8));
+      let pixel = &mut buffer[(y * WIDTH + x) * 4..][..4];
+      match bit {
+        0 => pixel.copy_from_slice(&[0x00, 0x00, 0x00, 0x00]),
+        _ => {
+          pixel[0..3].copy_from_slice(&overlay.color_at(x, y));
+          pixel[3] = 0xFF;
+        }
+      }
+    }
+  }
+}
+
+// Makes every lit pixel of a rendered screen partly transparent
+pub fn set_lit_alpha(screen: &mut RgbaImage, alpha: u8) {
+  for pixel in screen.pixels_mut().filter(|pixel| pixel[3] != 0) {
+    pixel[3] = alpha;
+  }
+}
+
+pub struct ScreenshotOptions<'a> {
+  // Without the overlay, every lit pixel is white
+  pub overlay: bool,
+  // Each emulated pixel becomes a scale x scale block
+  pub scale: u32,
+  // Drawn behind the screen, stretched to fit if needed
+  pub background: Option<&'a RgbaImage>,
+}
+
+impl Default for ScreenshotOptions<'_> {
+  fn default() -> Self {
+    ScreenshotOptions {
+      overlay: true,
+      scale: 1,
+      background: None,
+    }
+  }
+}
+
+// Composites a screen rendered by render_rgba into an opaque image
+pub fn compose_screenshot(screen: &RgbaImage, options: &ScreenshotOptions) -> RgbaImage {
+  let (width, height) = (screen.width() * options.scale.max(1), screen.height() * options.scale.max(1));
+  let mut screen = imageops::resize(screen, width, height, imageops::FilterType::Nearest);
+  let mut image = match options.background {
+    Some(background) => {
+      set_lit_alpha(&mut screen, BACKGROUND_ALPHA);
+      match background.dimensions() == (width, height) {
+        true => background.clone(),
+        false => imageops::resize(background, width, height, imageops::FilterType::Lanczos3),
+      }
+    }
+    None => RgbaImage::from_pixel(width, height, Rgba([0x00, 0x00, 0x00, 0xFF])),
+  };
+  imageops::overlay(&mut image, &screen, 0, 0);
+  // Blending can leave the alpha just short of opaque
+  for pixel in image.pixels_mut() {
+    pixel[3] = 0xFF;
+  }
+
+  image
+}
+
+// A file name that sorts by the (UTC) time it was taken, such as 2022-03-14_15-09-26.535.png
+pub fn timestamped_file_name(time: SystemTime, extension: &str) -> PathBuf {
+  let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+  let seconds = since_epoch.as_secs();
+  let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+  PathBuf::from(format!(
+    "{:04}-{:02}-{:02}_{:02}-{:02}-{:02}.{:03}.{}",
+    year, month, day,
+    seconds / 3600 % 24, seconds / 60 % 60, seconds % 60, since_epoch.subsec_millis(),
+    extension,
+  ))
+}
+
+// Converts days since 1970-01-01 into a Gregorian calendar date (http://howardhinnant.github.io/date_algorithms.html)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let days = days + 719468;
+  let era = days.div_euclid(146097);
+  let day_of_era = days.rem_euclid(146097);
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month_index = (5 * day_of_year + 2) / 153;
+  let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+  let month = match month_index < 10 {
+    true => month_index + 3,
+    false => month_index - 9,
+  } as u32;
+  let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+  (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rotation_and_overlay() {
+    let mut frame_buffer = [0; 0x1C00];
+    // The first byte is the bottom of the leftmost column
+    frame_buffer[0] = 0x01;
+    // Further along the bottom row is under the green strip for the remaining lives
+    frame_buffer[30 * 32] = 0x01;
+    // The top bit of the 27th byte of a column is y = 40, in the red band
+    frame_buffer[26] = 0x80;
+    let mut buffer = vec![0xAA; WIDTH * HEIGHT * 4];
+    render_rgba(&frame_buffer, &Overlay::midway(), &mut buffer);
+
+    let pixel = |x: usize, y: usize| buffer[(y * WIDTH + x) * 4..][..4].to_vec();
+    assert_eq!(pixel(0, 255), [0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(pixel(30, 255), [0x00, 0xFF, 0x00, 0xFF]);
+    assert_eq!(pixel(0, 40), [0xFF, 0x00, 0x00, 0xFF]);
+    assert_eq!(pixel(1, 255), [0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(buffer.iter().filter(|&&byte| byte == 0xFF).count(), 8);
+
+    render_rgba(&frame_buffer, &Overlay::monochrome(), &mut buffer);
+    assert_eq!(buffer.iter().filter(|&&byte| byte == 0xFF).count(), 12);
+  }
+
+  #[test]
+  fn overlay_file() {
+    let overlays = parse_overlays("
+      [[overlay]]
+      name = \"Amber\"
+      color = [255, 191, 0]
+
+      [[overlay]]
+      name = \"Red UFO\"
+      color = [255, 255, 255]
+      [[overlay.region]]
+      x = 0
+      y = 32
+      width = 224
+      height = 32
+      color = [255, 0, 0]
+    ").unwrap();
+    assert_eq!(overlays.len(), 2);
+    assert_eq!(overlays[0].color_at(100, 40), [0xFF, 0xBF, 0x00]);
+    assert_eq!(overlays[1].name, "Red UFO");
+    assert_eq!(overlays[1].color_at(100, 40), [0xFF, 0x00, 0x00]);
+    assert_eq!(overlays[1].color_at(100, 64), [0xFF, 0xFF, 0xFF]);
+
+    assert!(parse_overlays("").unwrap().is_empty());
+    assert!(parse_overlays("[[overlay]]\nname = \"No color\"").is_err());
+  }
+
+  #[test]
+  fn screenshot_composition() {
+    let mut screen = RgbaImage::new(2, 1);
+    screen.put_pixel(0, 0, Rgba([0xFF, 0x00, 0x00, 0xFF]));
+
+    let image = compose_screenshot(&screen, &ScreenshotOptions { scale: 2, ..Default::default() });
+    assert_eq!(image.dimensions(), (4, 2));
+    assert_eq!(*image.get_pixel(1, 1), Rgba([0xFF, 0x00, 0x00, 0xFF]));
+    assert_eq!(*image.get_pixel(2, 0), Rgba([0x00, 0x00, 0x00, 0xFF]));
+
+    // Lit pixels are blended with the background, and unlit pixels show it unchanged
+    let background = RgbaImage::from_pixel(2, 1, Rgba([0x00, 0x00, 0xFF, 0xFF]));
+    let image = compose_screenshot(&screen, &ScreenshotOptions { background: Some(&background), ..Default::default() });
+    assert_eq!(*image.get_pixel(1, 0), Rgba([0x00, 0x00, 0xFF, 0xFF]));
+    let blended = image.get_pixel(0, 0);
+    assert!(blended[0] > 0x80 && blended[2] > 0x00 && blended[2] < 0x80 && blended[3] == 0xFF, "{:?}", blended);
+  }
+
+  #[test]
+  fn timestamps() {
+    let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1_647_270_566_535);
+    assert_eq!(timestamped_file_name(time, "png"), PathBuf::from("2022-03-14_15-09-26.535.png"));
+    assert_eq!(timestamped_file_name(SystemTime::UNIX_EPOCH, "gif"), PathBuf::from("1970-01-01_00-00-00.000.gif"));
+    assert_eq!(civil_from_days(11016), (2000, 2, 29));
+  }
+}
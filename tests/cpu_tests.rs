@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use emulate_8080::cpm;
+
+// The test ROMs are not distributed with the emulator, so the tests that need them are ignored by default.
+// Copy them into roms/cpu_tests and run `cargo test --release -- --ignored`
+fn load_test_rom(name: &str) -> Vec<u8> {
+  let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "roms", "cpu_tests", name].iter().collect();
+  match std::fs::read(&path) {
+    Ok(program) => program,
+    Err(error) => panic!("Couldn't read {}: {}", path.display(), error),
+  }
+}
+
+#[test]
+fn bdos_console_output() {
+  let program = [
+    0x0E, 0x09, // MVI C, 9
+    0x11, 0x12, 0x01, // LXI D, $0112
+    0xCD, 0x05, 0x00, // CALL $0005
+    0x0E, 0x02, // MVI C, 2
+    0x1E, b'!', // MVI E, '!'
+    0xCD, 0x05, 0x00, // CALL $0005
+    0xC3, 0x00, 0x00, // JMP $0000
+    b'H', b'E', b'L', b'L', b'O', b'$',
+  ];
+  assert_eq!(cpm::run_program(&program), "HELLO!");
+}
+
+#[test]
+#[ignore = "needs roms/cpu_tests/*.COM"]
+fn tst8080() {
+  let program = load_test_rom("TST8080.COM");
+  let output = cpm::run_program(&program);
+  assert!(output.contains("CPU IS OPERATIONAL"), "{}", output);
+}
+
+#[test]
+#[ignore = "needs roms/cpu_tests/*.COM"]
+fn preliminary_8080() {
+  let program = load_test_rom("8080PRE.COM");
+  let output = cpm::run_program(&program);
+  assert!(output.contains("8080 Preliminary tests complete"), "{}", output);
+}
+
+#[test]
+#[ignore = "needs roms/cpu_tests/*.COM"]
+fn cputest() {
+  let program = load_test_rom("CPUTEST.COM");
+  let output = cpm::run_program(&program);
+  assert!(output.contains("CPU TESTS OK"), "{}", output);
+}
+
+// Takes several minutes unless built with --release
+#[test]
+#[ignore = "needs roms/cpu_tests/*.COM"]
+fn exerciser_8080() {
+  let program = load_test_rom("8080EXM.COM");
+  let output = cpm::run_program(&program);
+  assert!(output.contains("Tests complete"), "{}", output);
+  assert!(!output.contains("ERROR"), "{}", output);
+}